@@ -0,0 +1,480 @@
+use crate::dns::{DnsQuery, DnsResponse, Question, RData, RecordType, ResponseCode};
+use lru::LruCache;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The TTL on stale answers, as recommended by RFC 8767.
+const STALE_TTL: u32 = 30;
+/// How often to retry a question answered stale, as recommended by RFC 8767.
+const STALE_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// The fewest retries a stale entry gets before it is too old to serve.
+const MIN_STALE_REFRESHES: u32 = 4;
+/// How many times an entry has to be served before it is worth prefetching.
+const PREFETCH_MIN_HITS: u64 = 3;
+/// Prefetching starts once an entry is into the last 1/10th of its TTL.
+const PREFETCH_WINDOW: u32 = 10;
+
+/// What a cached response answers: the question, plus the query bits that change what the
+/// upstream puts in the response.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub question: Question,
+    /// The EDNS DO bit, asking for DNSSEC records along with the answer.
+    pub dnssec_ok: bool,
+    /// The CD bit, asking the upstream not to validate, which can let bogus answers through.
+    pub checking_disabled: bool,
+}
+
+impl CacheKey {
+    /// The key for a single-question query.
+    pub fn new(query: &DnsQuery) -> CacheKey {
+        return CacheKey {
+            question: query.questions[0].clone(),
+            dnssec_ok: query.edns.as_ref().is_some_and(|edns| edns.dnssec_ok),
+            checking_disabled: query.header.z & 0b001 != 0,
+        };
+    }
+}
+
+struct Entry {
+    response: DnsResponse,
+    stored: Instant,
+    expires: Instant,
+    hits: u64,
+    /// Whether a background refresh of this expired entry is already under way.
+    refreshing: bool,
+    /// Whether this entry is already being fetched again ahead of its expiry.
+    prefetching: bool,
+}
+
+/// Upstream responses kept for as long as their TTLs allow, keyed by the query they answer.
+/// Expired responses are kept for a while longer, to answer with when the upstreams are down.
+/// Once full, the least recently used entry makes way for each new one.
+pub struct Cache {
+    entries: Mutex<LruCache<CacheKey, Entry>>,
+    max_entries: usize,
+    max_stale: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stale: AtomicU64,
+    prefetches: AtomicU64,
+}
+
+impl Cache {
+    /// A cache holding at most `max_entries` responses, each of which may be served stale for
+    /// up to `max_stale` after it expires. With 0 entries nothing is cached.
+    pub fn new(max_entries: usize, max_stale: Duration) -> Cache {
+        return Cache {
+            entries: Mutex::new(LruCache::unbounded()),
+            max_entries,
+            max_stale,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            stale: AtomicU64::new(0),
+            prefetches: AtomicU64::new(0),
+        };
+    }
+
+    /// The cached response to `key`, with every TTL reduced by the time it has spent in
+    /// the cache.
+    pub fn get(&self, key: &CacheKey) -> Option<DnsResponse> {
+        let response = self.lookup(key, Instant::now());
+        let counter = match response {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        return response;
+    }
+
+    fn lookup(&self, key: &CacheKey, now: Instant) -> Option<DnsResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(key)?;
+        if entry.expires <= now {
+            if self.too_old(entry, now) {
+                entries.pop(key);
+            }
+            return None;
+        }
+        entry.hits += 1;
+        let age = (now - entry.stored).as_secs() as u32;
+        let mut response = entry.response.clone();
+        for record in response
+            .answers
+            .iter_mut()
+            .chain(&mut response.authority)
+            .chain(&mut response.additional)
+        {
+            record.ttl = record.ttl.saturating_sub(age);
+        }
+        return Some(response);
+    }
+
+    /// The expired response to `key`, if it is recent enough to serve while the upstreams
+    /// are unreachable, with every TTL set to 30 seconds.
+    pub fn get_stale(&self, key: &CacheKey) -> Option<DnsResponse> {
+        let mut response = self.lookup_stale(key, Instant::now())?;
+        for record in response
+            .answers
+            .iter_mut()
+            .chain(&mut response.authority)
+            .chain(&mut response.additional)
+        {
+            record.ttl = STALE_TTL;
+        }
+        self.stale.fetch_add(1, Ordering::Relaxed);
+        return Some(response);
+    }
+
+    fn lookup_stale(&self, key: &CacheKey, now: Instant) -> Option<DnsResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        if now < entry.expires || self.too_old(entry, now) {
+            return None;
+        }
+        return Some(entry.response.clone());
+    }
+
+    /// Whether an expired entry is past serving even stale. With a `max_stale` too long to
+    /// represent, nothing ever is.
+    fn too_old(&self, entry: &Entry, now: Instant) -> bool {
+        return entry
+            .expires
+            .checked_add(self.max_stale)
+            .is_some_and(|limit| limit <= now);
+    }
+
+    /// How often to retry the question of an entry being served stale: every 30 seconds, or
+    /// more often if `max_stale` is too short to fit a few retries in at that pace.
+    pub fn stale_refresh_interval(&self) -> Duration {
+        return STALE_REFRESH_INTERVAL.min(self.max_stale / MIN_STALE_REFRESHES);
+    }
+
+    /// Whether there is an expired response to `key` that can still be served stale.
+    pub fn is_stale(&self, key: &CacheKey) -> bool {
+        return self.lookup_stale(key, Instant::now()).is_some();
+    }
+
+    /// Marks the stale entry for `key` as being refreshed. Returns false if there is no
+    /// such entry or someone else is already refreshing it.
+    pub fn start_refresh(&self, key: &CacheKey) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        return match entries.peek_mut(key) {
+            Some(entry) if !entry.refreshing => {
+                entry.refreshing = true;
+                true
+            }
+            _ => false,
+        };
+    }
+
+    /// Marks the entry for `key` as being prefetched if it is popular and about to expire,
+    /// so that it can be fetched again before anyone misses it. Returns false if it is not
+    /// worth prefetching or someone else is already on it.
+    pub fn start_prefetch(&self, key: &CacheKey) -> bool {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.peek_mut(key) else {
+            return false;
+        };
+        let prefetch_from = entry.expires - (entry.expires - entry.stored) / PREFETCH_WINDOW;
+        if entry.prefetching
+            || entry.hits < PREFETCH_MIN_HITS
+            || now < prefetch_from
+            || entry.expires <= now
+        {
+            return false;
+        }
+        entry.prefetching = true;
+        self.prefetches.fetch_add(1, Ordering::Relaxed);
+        return true;
+    }
+
+    /// Stores a response if it is worth caching: a complete answer whose records all have a
+    /// TTL, or a negative answer (NXDOMAIN or NODATA) that carries an SOA record.
+    pub fn insert(&self, key: CacheKey, response: &DnsResponse) {
+        self.insert_at(key, response, Instant::now());
+    }
+
+    fn insert_at(&self, key: CacheKey, response: &DnsResponse, now: Instant) {
+        if self.max_entries == 0 || response.header.tc == 1 {
+            return;
+        }
+        let ttl = match cache_ttl(response) {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return,
+        };
+        let mut response = response.clone();
+        // Served negative answers carry their remaining negative TTL on the SOA record.
+        for record in &mut response.authority {
+            if record.rtype == RecordType::SOA {
+                record.ttl = record.ttl.min(ttl);
+            }
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        while entries.len() >= self.max_entries && !entries.contains(&key) {
+            entries.pop_lru();
+        }
+        entries.put(
+            key,
+            Entry {
+                response,
+                stored: now,
+                expires: now + Duration::from_secs(ttl as u64),
+                hits: 0,
+                refreshing: false,
+                prefetching: false,
+            },
+        );
+    }
+}
+
+/// How long a response may be cached for: as long as its shortest-lived record, and for
+/// negative answers no longer than the SOA minimum (RFC 2308). Negative answers without an SOA
+/// record are not cached.
+fn cache_ttl(response: &DnsResponse) -> Option<u32> {
+    let shortest = response
+        .answers
+        .iter()
+        .chain(&response.authority)
+        .chain(&response.additional)
+        .map(|record| record.ttl)
+        .min();
+    return match response.response_code() {
+        ResponseCode::NoError if !response.answers.is_empty() => shortest,
+        ResponseCode::NoError | ResponseCode::NXDomain => {
+            let minimum = response
+                .authority
+                .iter()
+                .find_map(|record| match record.data() {
+                    Ok(RData::SOA { minimum, .. }) => Some(minimum),
+                    _ => None,
+                })?;
+            Some(minimum.min(shortest.unwrap_or(minimum)))
+        }
+        _ => None,
+    };
+}
+
+impl Display for Cache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return writeln!(
+            f,
+            "Cache: {} of {} entries, {} hits, {} misses, {} stale answers, {} prefetches",
+            self.entries.lock().unwrap().len(),
+            self.max_entries,
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            self.stale.load(Ordering::Relaxed),
+            self.prefetches.load(Ordering::Relaxed)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DNSHeader, DnsClass, DnsQuery, Opcode, ResourceRecord};
+    use std::net::Ipv4Addr;
+
+    /// A response to an A query for `name` with a single answer, and its cache key.
+    fn response(name: &str, ttl: u32) -> (CacheKey, DnsResponse) {
+        let query = DnsQuery {
+            header: DNSHeader::error_reply(1, Opcode::Query, 1, ResponseCode::NoError),
+            questions: vec![Question {
+                name: name.parse().unwrap(),
+                qtype: RecordType::A,
+                qclass: DnsClass::IN,
+            }],
+            edns: None,
+        };
+        let key = CacheKey::new(&query);
+        let mut response = DnsResponse::server_failure(&query);
+        response.header.rcode = ResponseCode::NoError;
+        response.answers.push(ResourceRecord::new(
+            query.questions[0].name.clone(),
+            DnsClass::IN,
+            ttl,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        ));
+        return (key, response);
+    }
+
+    #[test]
+    fn test_get_decrements_ttl() {
+        let cache = Cache::new(10, Duration::ZERO);
+        let (key, response) = response("example.com", 300);
+        let now = Instant::now();
+        cache.insert_at(key.clone(), &response, now);
+
+        let cached = cache.lookup(&key, now + Duration::from_secs(100));
+        assert_eq!(cached.unwrap().answers[0].ttl, 200);
+        assert!(cache.lookup(&key, now + Duration::from_secs(300)).is_none());
+        assert_eq!(cache.entries.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_key_ignores_case() {
+        let cache = Cache::new(10, Duration::ZERO);
+        let (key, answer) = response("example.com", 300);
+        cache.insert(key, &answer);
+        let (other_case, _) = response("EXAMPLE.com", 300);
+        assert!(cache.get(&other_case).is_some());
+    }
+
+    #[test]
+    fn test_key_includes_dnssec_bits() {
+        let cache = Cache::new(10, Duration::ZERO);
+        let (key, answer) = response("example.com", 300);
+        cache.insert(key.clone(), &answer);
+        let dnssec_ok = CacheKey {
+            dnssec_ok: true,
+            ..key.clone()
+        };
+        assert!(cache.get(&dnssec_ok).is_none());
+        let checking_disabled = CacheKey {
+            checking_disabled: true,
+            ..key.clone()
+        };
+        assert!(cache.get(&checking_disabled).is_none());
+        assert!(cache.get(&key).is_some());
+    }
+
+    #[test]
+    fn test_skips_uncacheable_responses() {
+        let cache = Cache::new(10, Duration::ZERO);
+        let (key, mut response) = response("example.com", 0);
+        cache.insert(key.clone(), &response);
+        response.answers[0].ttl = 300;
+        response.header.tc = 1;
+        cache.insert(key.clone(), &response);
+        response.header.tc = 0;
+        response.header.rcode = ResponseCode::ServFail;
+        cache.insert(key.clone(), &response);
+        assert!(cache.get(&key).is_none());
+
+        let cache = Cache::new(0, Duration::ZERO);
+        response.header.rcode = ResponseCode::NoError;
+        cache.insert(key.clone(), &response);
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[test]
+    fn test_caches_negative_answers_for_soa_minimum() {
+        let cache = Cache::new(10, Duration::ZERO);
+        let (key, mut nxdomain) = response("typo.example", 300);
+        nxdomain.answers.clear();
+        nxdomain.header.rcode = ResponseCode::NXDomain;
+        cache.insert(key.clone(), &nxdomain);
+        assert!(cache.get(&key).is_none());
+
+        nxdomain.authority.push(ResourceRecord::new(
+            "example".parse().unwrap(),
+            DnsClass::IN,
+            3600,
+            RData::SOA {
+                mname: "ns.example".parse().unwrap(),
+                rname: "hostmaster.example".parse().unwrap(),
+                serial: 1,
+                refresh: 7200,
+                retry: 900,
+                expire: 86400,
+                minimum: 60,
+            },
+        ));
+        let now = Instant::now();
+        cache.insert_at(key.clone(), &nxdomain, now);
+        let cached = cache.lookup(&key, now + Duration::from_secs(20)).unwrap();
+        assert_eq!(cached.response_code(), ResponseCode::NXDomain);
+        assert_eq!(cached.authority[0].ttl, 40);
+        assert!(cache.lookup(&key, now + Duration::from_secs(60)).is_none());
+
+        // NODATA: the name exists but has no records of the asked type.
+        let mut nodata = nxdomain;
+        nodata.header.rcode = ResponseCode::NoError;
+        cache.insert(key.clone(), &nodata);
+        assert_eq!(cache.get(&key).unwrap().authority[0].ttl, 60);
+    }
+
+    #[test]
+    fn test_serves_stale_within_max_stale() {
+        let cache = Cache::new(10, Duration::from_secs(3600));
+        let (key, response) = response("example.com", 300);
+        cache.insert(key.clone(), &response);
+        assert!(cache.get_stale(&key).is_none());
+
+        let expired = Instant::now() - Duration::from_secs(400);
+        cache.insert_at(key.clone(), &response, expired);
+        assert!(cache.get(&key).is_none());
+        assert_eq!(cache.get_stale(&key).unwrap().answers[0].ttl, STALE_TTL);
+        assert!(cache.start_refresh(&key));
+        assert!(!cache.start_refresh(&key));
+        assert_eq!(cache.stale_refresh_interval(), STALE_REFRESH_INTERVAL);
+        let brief = Cache::new(10, Duration::from_secs(60));
+        assert_eq!(brief.stale_refresh_interval(), Duration::from_secs(15));
+
+        let forever = Cache::new(10, Duration::MAX);
+        forever.insert_at(key.clone(), &response, expired);
+        assert!(forever.is_stale(&key));
+
+        let long_expired = Instant::now() - Duration::from_secs(300 + 3600);
+        cache.insert_at(key.clone(), &response, long_expired);
+        assert!(!cache.is_stale(&key));
+        assert!(cache.get(&key).is_none());
+        assert_eq!(cache.entries.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_prefetches_popular_entries_near_expiry() {
+        let cache = Cache::new(10, Duration::ZERO);
+        let (key, response) = response("example.com", 100);
+        cache.insert_at(
+            key.clone(),
+            &response,
+            Instant::now() - Duration::from_secs(80),
+        );
+        for _ in 0..PREFETCH_MIN_HITS {
+            assert!(cache.get(&key).is_some());
+        }
+        // Popular, but with a fifth of its TTL left it is not due yet.
+        assert!(!cache.start_prefetch(&key));
+
+        cache.insert_at(
+            key.clone(),
+            &response,
+            Instant::now() - Duration::from_secs(95),
+        );
+        assert!(cache.get(&key).is_some());
+        // Due, but not popular enough.
+        assert!(!cache.start_prefetch(&key));
+        for _ in 1..PREFETCH_MIN_HITS {
+            assert!(cache.get(&key).is_some());
+        }
+        assert!(cache.start_prefetch(&key));
+        assert!(!cache.start_prefetch(&key));
+        assert!(cache.to_string().ends_with(", 1 prefetches\n"));
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = Cache::new(2, Duration::ZERO);
+        let (first, response_a) = response("a.example", 300);
+        let (second, response_b) = response("b.example", 300);
+        let (third, response_c) = response("c.example", 300);
+        cache.insert(first.clone(), &response_a);
+        cache.insert(second.clone(), &response_b);
+        assert!(cache.get(&first).is_some());
+        cache.insert(third.clone(), &response_c);
+
+        assert!(cache.get(&first).is_some());
+        assert!(cache.get(&second).is_none());
+        assert!(cache.get(&third).is_some());
+        assert_eq!(
+            cache.to_string(),
+            "Cache: 2 of 2 entries, 3 hits, 1 misses, 0 stale answers, 0 prefetches\n"
+        );
+    }
+}
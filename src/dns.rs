@@ -1,31 +1,112 @@
 use byteorder::{BigEndian, ByteOrder};
-use nom::AsBytes;
+use std::collections::HashMap;
+use thiserror::Error;
+
+mod edns;
+mod name;
+mod rdata;
+mod types;
+
+pub use edns::{Edns, DEFAULT_UDP_PAYLOAD_SIZE, MIN_UDP_PAYLOAD_SIZE};
+pub use name::DomainName;
+pub use rdata::RData;
+pub use types::{DnsClass, Opcode, RecordType, ResponseCode};
+
+/// Everything that can go wrong while decoding a message off the wire.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("header is {0} bytes long, expected 12")]
+    TruncatedHeader(usize),
+    #[error("message ends unexpectedly at offset {0}")]
+    UnexpectedEnd(usize),
+    #[error("label of length {len} at offset {offset} overruns the message")]
+    LabelOverrun { offset: usize, len: usize },
+    #[error("unsupported label type {0:#04x}")]
+    InvalidLabelType(u8),
+    #[error("compression pointer at offset {0} does not point backwards")]
+    PointerLoop(usize),
+    #[error("domain name at offset {0} is longer than 255 bytes")]
+    NameTooLong(usize),
+    #[error("RDATA of type {rtype} has an invalid length of {len} bytes")]
+    BadRdataLength { rtype: RecordType, len: usize },
+    #[error("message carries more than one OPT record")]
+    DuplicateOpt,
+    #[error("{section} count is {declared} but the message only holds {found}")]
+    BadCount {
+        section: &'static str,
+        declared: u16,
+        found: u16,
+    },
+}
+
+fn read_slice(buffer: &[u8], pos: usize, len: usize) -> Result<&[u8], ParseError> {
+    return buffer
+        .get(pos..pos + len)
+        .ok_or(ParseError::UnexpectedEnd(buffer.len().min(pos)));
+}
+
+fn read_u8(buffer: &[u8], pos: usize) -> Result<u8, ParseError> {
+    return Ok(read_slice(buffer, pos, 1)?[0]);
+}
+
+fn read_u16(buffer: &[u8], pos: usize) -> Result<u16, ParseError> {
+    return Ok(BigEndian::read_u16(read_slice(buffer, pos, 2)?));
+}
+
+fn read_u32(buffer: &[u8], pos: usize) -> Result<u32, ParseError> {
+    return Ok(BigEndian::read_u32(read_slice(buffer, pos, 4)?));
+}
 
 #[derive(Debug, Clone)]
 pub struct DnsQuery {
     pub header: DNSHeader,
     pub questions: Vec<Question>,
+    pub edns: Option<Edns>,
 }
 
-#[derive(Debug)]
+/// A response message. The OPT pseudo-record, if any, lives in `edns` rather than `additional`,
+/// and the section counts in `header` are recomputed from the sections on serialization.
+#[derive(Debug, Clone)]
 pub struct DnsResponse {
     pub header: DNSHeader,
     pub questions: Vec<Question>,
     pub answers: Vec<ResourceRecord>,
+    pub authority: Vec<ResourceRecord>,
+    pub additional: Vec<ResourceRecord>,
+    pub edns: Option<Edns>,
 }
 
 impl DnsQuery {
-    pub fn deserialize(buffer: &[u8]) -> DnsQuery {
-        let header = DNSHeader::deserialize(&buffer[..12]);
-        let (questions, _) = Question::deserialize(&buffer[12..], header.qdcount);
-        return DnsQuery { header, questions };
+    pub fn deserialize(buffer: &[u8]) -> Result<DnsQuery, ParseError> {
+        let header = DNSHeader::deserialize(buffer)?;
+        let (questions, pos) = Question::deserialize(buffer, 12, header.qdcount)?;
+        let (_, pos) = ResourceRecord::deserialize(buffer, pos, header.ancount, "answer")?;
+        let (_, pos) = ResourceRecord::deserialize(buffer, pos, header.nscount, "authority")?;
+        let (mut additional, _) =
+            ResourceRecord::deserialize(buffer, pos, header.arcount, "additional")?;
+        let edns = Edns::take_from(&mut additional)?;
+        return Ok(DnsQuery {
+            header,
+            questions,
+            edns,
+        });
     }
 
+    /// Writes the questions and OPT record. Any other records a client sent are not kept.
     pub fn serialize(&self) -> Vec<u8> {
         let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.header.serialize());
+        let mut names = HashMap::new();
+        let mut header = self.header.clone();
+        header.qdcount = self.questions.len() as u16;
+        header.ancount = 0;
+        header.nscount = 0;
+        header.arcount = self.edns.is_some() as u16;
+        buffer.extend_from_slice(&header.serialize());
         for question in &self.questions {
-            buffer.extend_from_slice(&question.serialize());
+            question.serialize_compressed(&mut buffer, &mut names);
+        }
+        if let Some(edns) = &self.edns {
+            edns.to_record().serialize_compressed(&mut buffer, &mut names);
         }
         return buffer;
     }
@@ -43,45 +124,128 @@ impl DnsQuery {
 }
 
 impl DnsResponse {
-    pub fn deserialize(buffer: &[u8]) -> DnsResponse {
-        let header = DNSHeader::deserialize(&buffer[..12]);
-        let (questions, new_pos) = Question::deserialize(&buffer[12..], header.qdcount);
-        let answers = if header.ancount > 0 {
-            ResourceRecord::deserialize(&buffer[12 + new_pos..], header.ancount)
-        } else {
-            Vec::new()
-        };
-        return DnsResponse {
+    pub fn deserialize(buffer: &[u8]) -> Result<DnsResponse, ParseError> {
+        let header = DNSHeader::deserialize(buffer)?;
+        let (questions, pos) = Question::deserialize(buffer, 12, header.qdcount)?;
+        let (answers, pos) = ResourceRecord::deserialize(buffer, pos, header.ancount, "answer")?;
+        let (authority, pos) =
+            ResourceRecord::deserialize(buffer, pos, header.nscount, "authority")?;
+        let (mut additional, _) =
+            ResourceRecord::deserialize(buffer, pos, header.arcount, "additional")?;
+        let edns = Edns::take_from(&mut additional)?;
+        return Ok(DnsResponse {
             header,
             questions,
             answers,
+            authority,
+            additional,
+            edns,
+        });
+    }
+
+    /// Builds a FORMERR reply to a request that could not be parsed. Returns `None` when the
+    /// request is too short to even carry an ID, since there is nobody to address a reply to.
+    pub fn format_error(request: &[u8]) -> Option<DnsResponse> {
+        let id = read_u16(request, 0).ok()?;
+        let flags = read_u8(request, 2).unwrap_or(0);
+        let opcode = Opcode::from(((flags >> 3) & 0b1111) as u16);
+        let header = DNSHeader::error_reply(id, opcode, flags & 0b1, ResponseCode::FormErr);
+        return Some(DnsResponse {
+            header,
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authority: Vec::new(),
+            additional: Vec::new(),
+            edns: None,
+        });
+    }
+
+    /// Builds a SERVFAIL reply to `query`, echoing its ID and questions.
+    pub fn server_failure(query: &DnsQuery) -> DnsResponse {
+        return DnsResponse::error(query, ResponseCode::ServFail);
+    }
+
+    /// Builds a NOTIMP reply to `query`, for opcodes the forwarder doesn't support.
+    pub fn not_implemented(query: &DnsQuery) -> DnsResponse {
+        return DnsResponse::error(query, ResponseCode::NotImp);
+    }
+
+    fn error(query: &DnsQuery, rcode: ResponseCode) -> DnsResponse {
+        let mut header =
+            DNSHeader::error_reply(query.header.id, query.header.opcode, query.header.rd, rcode);
+        header.qdcount = query.questions.len() as u16;
+        return DnsResponse {
+            header,
+            questions: query.questions.clone(),
+            answers: Vec::new(),
+            authority: Vec::new(),
+            additional: Vec::new(),
+            edns: query.edns.as_ref().map(|_| Edns::new(DEFAULT_UDP_PAYLOAD_SIZE)),
         };
     }
 
+    /// The full response code, including the extended bits carried by EDNS.
+    pub fn response_code(&self) -> ResponseCode {
+        return match &self.edns {
+            Some(edns) => edns.response_code(self.header.rcode),
+            None => self.header.rcode,
+        };
+    }
+
+    /// Writes the message with names compressed against everything written before them.
     pub fn serialize(&self) -> Vec<u8> {
         let mut buffer = Vec::new();
-        buffer.extend_from_slice(&self.header.serialize());
+        let mut names = HashMap::new();
+        let mut header = self.header.clone();
+        header.qdcount = self.questions.len() as u16;
+        header.ancount = self.answers.len() as u16;
+        header.nscount = self.authority.len() as u16;
+        header.arcount = (self.additional.len() + self.edns.is_some() as usize) as u16;
+        buffer.extend_from_slice(&header.serialize());
         for question in &self.questions {
-            buffer.extend_from_slice(&question.serialize());
+            question.serialize_compressed(&mut buffer, &mut names);
         }
-        for answer in &self.answers {
-            buffer.extend_from_slice(&answer.serialize());
+        for record in self.answers.iter().chain(&self.authority).chain(&self.additional) {
+            record.serialize_compressed(&mut buffer, &mut names);
+        }
+        if let Some(edns) = &self.edns {
+            edns.to_record().serialize_compressed(&mut buffer, &mut names);
         }
         return buffer;
     }
+
+    /// Writes the message, or if it would not fit in `max_size` bytes, just its header with TC
+    /// set plus the question and OPT record, so the client knows to retry over TCP.
+    pub fn serialize_truncated(&self, max_size: usize) -> Vec<u8> {
+        let buffer = self.serialize();
+        if buffer.len() <= max_size {
+            return buffer;
+        }
+        let mut header = self.header.clone();
+        header.tc = 1;
+        let truncated = DnsResponse {
+            header,
+            questions: self.questions.clone(),
+            answers: Vec::new(),
+            authority: Vec::new(),
+            additional: Vec::new(),
+            edns: self.edns.clone(),
+        };
+        return truncated.serialize();
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct DNSHeader {
     pub id: u16,
     pub qr: u8,
-    pub opcode: u8,
+    pub opcode: Opcode,
     pub aa: u8,
     pub tc: u8,
     pub rd: u8,
     pub ra: u8,
     pub z: u8,
-    pub rcode: u8,
+    pub rcode: ResponseCode,
     pub qdcount: u16,
     pub ancount: u16,
     pub nscount: u16,
@@ -93,8 +257,10 @@ impl DNSHeader {
         let mut buffer = [0; 12];
         buffer[0] = (self.id >> 8) as u8;
         buffer[1] = self.id as u8;
-        buffer[2] = (self.qr << 7) | (self.opcode << 3) | (self.aa << 2) | (self.tc << 1) | self.rd;
-        buffer[3] = (self.ra << 7) | (self.z << 4) | self.rcode;
+        let opcode = u16::from(self.opcode) as u8 & 0b1111;
+        let rcode = u16::from(self.rcode) as u8 & 0b1111;
+        buffer[2] = (self.qr << 7) | (opcode << 3) | (self.aa << 2) | (self.tc << 1) | self.rd;
+        buffer[3] = (self.ra << 7) | (self.z << 4) | rcode;
         buffer[4] = (self.qdcount >> 8) as u8;
         buffer[5] = self.qdcount as u8;
         buffer[6] = (self.ancount >> 8) as u8;
@@ -106,21 +272,43 @@ impl DNSHeader {
         return buffer;
     }
 
-    pub fn deserialize(buffer: &[u8]) -> DNSHeader {
+    /// Header for a reply that carries no records, only `rcode`.
+    pub fn error_reply(id: u16, opcode: Opcode, rd: u8, rcode: ResponseCode) -> DNSHeader {
+        return DNSHeader {
+            id,
+            qr: 1,
+            opcode,
+            aa: 0,
+            tc: 0,
+            rd,
+            ra: 1,
+            z: 0,
+            rcode,
+            qdcount: 0,
+            ancount: 0,
+            nscount: 0,
+            arcount: 0,
+        };
+    }
+
+    pub fn deserialize(buffer: &[u8]) -> Result<DNSHeader, ParseError> {
+        if buffer.len() < 12 {
+            return Err(ParseError::TruncatedHeader(buffer.len()));
+        }
         let id = BigEndian::read_u16(&buffer[0..2]);
         let qr = buffer[2] >> 7;
-        let opcode = (buffer[2] >> 3) & 0b1111;
+        let opcode = Opcode::from(((buffer[2] >> 3) & 0b1111) as u16);
         let aa = (buffer[2] >> 2) & 0b1;
         let tc = (buffer[2] >> 1) & 0b1;
         let rd = buffer[2] & 0b1;
         let ra = buffer[3] >> 7;
         let z = (buffer[3] >> 4) & 0b111;
-        let rcode = buffer[3] & 0b1111;
+        let rcode = ResponseCode::from((buffer[3] & 0b1111) as u16);
         let qdcount = BigEndian::read_u16(&buffer[4..6]);
         let ancount = BigEndian::read_u16(&buffer[6..8]);
         let nscount = BigEndian::read_u16(&buffer[8..10]);
         let arcount = BigEndian::read_u16(&buffer[10..12]);
-        return DNSHeader {
+        return Ok(DNSHeader {
             id,
             qr,
             opcode,
@@ -134,94 +322,104 @@ impl DNSHeader {
             ancount,
             nscount,
             arcount,
-        };
+        });
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Question {
-    pub labels: Vec<String>,
-    pub qtype: u16,
-    pub qclass: u16,
+    pub name: DomainName,
+    pub qtype: RecordType,
+    pub qclass: DnsClass,
 }
 
 impl Question {
-    pub fn deserialize(buffer: &[u8], qcount: u16) -> (Vec<Question>, usize) {
-        let mut jumped = false;
-        let mut pos = 0;
+    /// Reads `qcount` questions starting at `pos` in the full message `buffer`, returning them
+    /// along with the offset of whatever follows.
+    pub fn deserialize(
+        buffer: &[u8],
+        pos: usize,
+        qcount: u16,
+    ) -> Result<(Vec<Question>, usize), ParseError> {
+        let mut pos = pos;
         let mut questions = Vec::new();
-        for _ in 0..qcount {
-            let mut labels = Vec::new();
-            'label: loop {
-                let len = buffer[pos] as usize;
-                if len & 0xC0 == 0xC0 {
-                    if !jumped {
-                        pos = ((BigEndian::read_u16(&buffer[pos..pos + 2]) - 0b1100000000000000)
-                            - 12) as usize;
-                        jumped = true;
-                        continue 'label;
-                    } else {
-                        break 'label;
-                    }
-                }
-                pos += 1;
-                if len == 0 {
-                    break 'label;
-                }
-                let label = String::from_utf8_lossy(&buffer[pos..pos + len]);
-                labels.push(label.into_owned());
-                pos += len;
+        for found in 0..qcount {
+            if pos >= buffer.len() {
+                return Err(ParseError::BadCount {
+                    section: "question",
+                    declared: qcount,
+                    found,
+                });
             }
-            let qtype = BigEndian::read_u16(&buffer[pos..pos + 2]);
+            let (name, next) = DomainName::deserialize(buffer, pos)?;
+            pos = next;
+            let qtype = RecordType::from(read_u16(buffer, pos)?);
             pos += 2;
-            let qclass = BigEndian::read_u16(&buffer[pos..pos + 2]);
+            let qclass = DnsClass::from(read_u16(buffer, pos)?);
             pos += 2;
             questions.push(Question {
-                labels,
+                name,
                 qtype,
                 qclass,
             });
         }
-        return (questions, pos);
+        return Ok((questions, pos));
     }
 
+    #[cfg(test)]
     pub fn serialize(&self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        for label in &self.labels {
-            buffer.push(label.len() as u8);
-            buffer.extend_from_slice(label.as_bytes());
-        }
-        buffer.push(0);
-        buffer.push((self.qtype >> 8) as u8);
-        buffer.push(self.qtype as u8);
-        buffer.push((self.qclass >> 8) as u8);
-        buffer.push(self.qclass as u8);
-        return buffer.as_bytes().to_owned();
+        let mut buffer = self.name.serialize();
+        buffer.extend_from_slice(&u16::from(self.qtype).to_be_bytes());
+        buffer.extend_from_slice(&u16::from(self.qclass).to_be_bytes());
+        return buffer;
+    }
+
+    pub fn serialize_compressed(
+        &self,
+        buffer: &mut Vec<u8>,
+        names: &mut HashMap<DomainName, usize>,
+    ) {
+        self.name.serialize_compressed(buffer, names);
+        buffer.extend_from_slice(&u16::from(self.qtype).to_be_bytes());
+        buffer.extend_from_slice(&u16::from(self.qclass).to_be_bytes());
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ResourceRecord {
-    pub name: Vec<String>,
-    pub rtype: u16,
-    pub class: u16,
+    pub name: DomainName,
+    pub rtype: RecordType,
+    pub class: DnsClass,
     pub ttl: u32,
+    #[allow(dead_code)]
     pub rdlength: u16,
     pub rdata: Vec<u8>,
 }
 
 impl ResourceRecord {
+    #[allow(dead_code)]
+    pub fn new(name: DomainName, class: DnsClass, ttl: u32, data: RData) -> ResourceRecord {
+        let rdata = data.serialize();
+        return ResourceRecord {
+            name,
+            rtype: data.rtype(),
+            class,
+            ttl,
+            rdlength: rdata.len() as u16,
+            rdata,
+        };
+    }
+
+    /// Decodes the RDATA according to the record's type.
+    pub fn data(&self) -> Result<RData, ParseError> {
+        return RData::deserialize(&self.rdata, 0, self.rtype);
+    }
+
+    #[cfg(test)]
     pub fn serialize(&self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        for label in &self.name {
-            buffer.push(label.len() as u8);
-            buffer.extend_from_slice(label.as_bytes());
-        }
-        buffer.push(0);
-        buffer.push((self.rtype >> 8) as u8);
-        buffer.push(self.rtype as u8);
-        buffer.push((self.class >> 8) as u8);
-        buffer.push(self.class as u8);
+        let mut buffer = self.name.serialize();
+        buffer.extend_from_slice(&u16::from(self.rtype).to_be_bytes());
+        buffer.extend_from_slice(&u16::from(self.class).to_be_bytes());
         buffer.push((self.ttl >> 24) as u8);
         buffer.push((self.ttl >> 16) as u8);
         buffer.push((self.ttl >> 8) as u8);
@@ -232,35 +430,76 @@ impl ResourceRecord {
         return buffer;
     }
 
-    pub fn deserialize(buffer: &[u8], rcount: u16) -> Vec<ResourceRecord> {
-        let mut pos = 0;
+    /// Appends the record to `buffer`, which holds the message written so far, compressing the
+    /// owner name and any compressible names in the RDATA. RDATA that doesn't decode is copied
+    /// as it is.
+    pub fn serialize_compressed(
+        &self,
+        buffer: &mut Vec<u8>,
+        names: &mut HashMap<DomainName, usize>,
+    ) {
+        self.name.serialize_compressed(buffer, names);
+        buffer.extend_from_slice(&u16::from(self.rtype).to_be_bytes());
+        buffer.extend_from_slice(&u16::from(self.class).to_be_bytes());
+        buffer.extend_from_slice(&self.ttl.to_be_bytes());
+        let rdlength_pos = buffer.len();
+        buffer.extend_from_slice(&[0, 0]);
+        match self.data() {
+            Ok(data) => data.serialize_compressed(buffer, names),
+            Err(_) => buffer.extend_from_slice(&self.rdata),
+        }
+        let rdlength = (buffer.len() - rdlength_pos - 2) as u16;
+        buffer[rdlength_pos..rdlength_pos + 2].copy_from_slice(&rdlength.to_be_bytes());
+    }
+
+    /// Reads the `rcount` records of `section` starting at `pos` in the full message `buffer`,
+    /// returning them along with the offset of whatever follows.
+    pub fn deserialize(
+        buffer: &[u8],
+        pos: usize,
+        rcount: u16,
+        section: &'static str,
+    ) -> Result<(Vec<ResourceRecord>, usize), ParseError> {
+        let mut pos = pos;
         let mut records = Vec::new();
-        for _ in 0..rcount {
-            let mut labels = Vec::new();
-            'label: loop {
-                let len = buffer[pos] as usize;
-                if len == 0 {
-                    break 'label;
-                }
-                let label = String::from_utf8_lossy(&buffer[pos + 1..pos + len + 1]);
-                labels.push(label.into_owned());
-                pos += len + 1;
+        for found in 0..rcount {
+            if pos >= buffer.len() {
+                return Err(ParseError::BadCount {
+                    section,
+                    declared: rcount,
+                    found,
+                });
             }
-            pos += 1;
-            let rtype = ((buffer[pos] as u16) << 8) | buffer[pos + 1] as u16;
+            let (name, next) = DomainName::deserialize(buffer, pos)?;
+            pos = next;
+            let rtype = RecordType::from(read_u16(buffer, pos)?);
             pos += 2;
-            let class = ((buffer[pos] as u16) << 8) | buffer[pos + 1] as u16;
+            let class = DnsClass::from(read_u16(buffer, pos)?);
             pos += 2;
-            let ttl = ((buffer[pos] as u32) << 24)
-                | ((buffer[pos + 1] as u32) << 16)
-                | ((buffer[pos + 2] as u32) << 8)
-                | buffer[pos + 3] as u32;
+            let ttl = read_u32(buffer, pos)?;
             pos += 4;
-            let rdlength = ((buffer[pos] as u16) << 8) | buffer[pos + 1] as u16;
+            let mut rdlength = read_u16(buffer, pos)?;
             pos += 2;
-            let rdata = buffer[pos..].to_vec();
+            let end = pos + rdlength as usize;
+            let raw = read_slice(buffer, pos, rdlength as usize)?;
+            // Pointers are only meaningful within the message they came from, and we always relay
+            // records in a message of our own, so names inside RDATA are stored expanded.
+            let rdata = match rtype {
+                RecordType::NS
+                | RecordType::CNAME
+                | RecordType::PTR
+                | RecordType::MX
+                | RecordType::SOA
+                | RecordType::SRV => {
+                    let expanded = RData::deserialize(&buffer[..end], pos, rtype)?.serialize();
+                    rdlength = expanded.len() as u16;
+                    expanded
+                }
+                _ => raw.to_vec(),
+            };
+            pos = end;
             records.push(ResourceRecord {
-                name: labels,
+                name,
                 rtype,
                 class,
                 ttl,
@@ -268,7 +507,7 @@ impl ResourceRecord {
                 rdata,
             });
         }
-        return records;
+        return Ok((records, pos));
     }
 }
 
@@ -276,18 +515,22 @@ impl ResourceRecord {
 mod tests {
     use super::*;
 
+    fn name(s: &str) -> DomainName {
+        return s.parse().unwrap();
+    }
+
     #[test]
     fn test_dns_header_serialize() {
         let header = DNSHeader {
             id: 0x1234,
             qr: 0,
-            opcode: 0,
+            opcode: Opcode::Query,
             aa: 0,
             tc: 0,
             rd: 1,
             ra: 0,
             z: 0,
-            rcode: 0,
+            rcode: ResponseCode::NoError,
             qdcount: 1,
             ancount: 0,
             nscount: 0,
@@ -300,16 +543,16 @@ mod tests {
     #[test]
     fn test_dns_header_deserialize() {
         let buffer = [0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
-        let header = DNSHeader::deserialize(&buffer);
+        let header = DNSHeader::deserialize(&buffer).unwrap();
         assert_eq!(header.id, 0x1234);
         assert_eq!(header.qr, 0);
-        assert_eq!(header.opcode, 0);
+        assert_eq!(header.opcode, Opcode::Query);
         assert_eq!(header.aa, 0);
         assert_eq!(header.tc, 0);
         assert_eq!(header.rd, 1);
         assert_eq!(header.ra, 0);
         assert_eq!(header.z, 0);
-        assert_eq!(header.rcode, 0);
+        assert_eq!(header.rcode, ResponseCode::NoError);
         assert_eq!(header.qdcount, 1);
         assert_eq!(header.ancount, 0);
         assert_eq!(header.nscount, 0);
@@ -319,9 +562,9 @@ mod tests {
     #[test]
     fn test_question_serialize() {
         let question = Question {
-            labels: vec!["www".to_string(), "example".to_string(), "com".to_string()],
-            qtype: 1,
-            qclass: 1,
+            name: name("www.example.com"),
+            qtype: RecordType::A,
+            qclass: DnsClass::IN,
         };
         let serialized = question.serialize();
         assert_eq!(serialized, [3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1]);
@@ -330,18 +573,18 @@ mod tests {
     #[test]
     fn test_question_deserialize() {
         let buffer = [3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1];
-        let (questions, _) = Question::deserialize(&buffer, 1);
-        assert_eq!(questions[0].labels, vec!["www".to_string(), "example".to_string(), "com".to_string()]);
-        assert_eq!(questions[0].qtype, 1);
-        assert_eq!(questions[0].qclass, 1);
+        let (questions, _) = Question::deserialize(&buffer, 0, 1).unwrap();
+        assert_eq!(questions[0].name, name("www.example.com"));
+        assert_eq!(questions[0].qtype, RecordType::A);
+        assert_eq!(questions[0].qclass, DnsClass::IN);
     }
 
     #[test]
     fn test_resource_record_serialize() {
         let record = ResourceRecord {
-            name: vec!["www".to_string(), "example".to_string(), "com".to_string()],
-            rtype: 1,
-            class: 1,
+            name: name("www.example.com"),
+            rtype: RecordType::A,
+            class: DnsClass::IN,
             ttl: 0,
             rdlength: 4,
             rdata: vec![127, 0, 0, 1],
@@ -353,10 +596,10 @@ mod tests {
     #[test]
     fn test_resource_record_deserialize() {
         let buffer = [3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 4, 127, 0, 0, 1];
-        let records = ResourceRecord::deserialize(&buffer, 1);
-        assert_eq!(records[0].name, vec!["www".to_string(), "example".to_string(), "com".to_string()]);
-        assert_eq!(records[0].rtype, 1);
-        assert_eq!(records[0].class, 1);
+        let (records, _) = ResourceRecord::deserialize(&buffer, 0, 1, "answer").unwrap();
+        assert_eq!(records[0].name, name("www.example.com"));
+        assert_eq!(records[0].rtype, RecordType::A);
+        assert_eq!(records[0].class, DnsClass::IN);
         assert_eq!(records[0].ttl, 0);
         assert_eq!(records[0].rdlength, 4);
         assert_eq!(records[0].rdata, vec![127, 0, 0, 1]);
@@ -368,23 +611,24 @@ mod tests {
             header: DNSHeader {
                 id: 0x1234,
                 qr: 0,
-                opcode: 0,
+                opcode: Opcode::Query,
                 aa: 0,
                 tc: 0,
                 rd: 1,
                 ra: 0,
                 z: 0,
-                rcode: 0,
+                rcode: ResponseCode::NoError,
                 qdcount: 1,
                 ancount: 0,
                 nscount: 0,
                 arcount: 0,
             },
             questions: vec![Question {
-                labels: vec!["www".to_string(), "example".to_string(), "com".to_string()],
-                qtype: 1,
-                qclass: 1,
+                name: name("www.example.com"),
+                qtype: RecordType::A,
+                qclass: DnsClass::IN,
             }],
+            edns: None,
         };
         let serialized = query.serialize();
         assert_eq!(serialized, SERIALIZED_DNS_QUERY_SINGLE_QUESTION);
@@ -396,13 +640,13 @@ mod tests {
             header: DNSHeader {
                 id: 0x1234,
                 qr: 0,
-                opcode: 0,
+                opcode: Opcode::Query,
                 aa: 0,
                 tc: 0,
                 rd: 1,
                 ra: 0,
                 z: 0,
-                rcode: 0,
+                rcode: ResponseCode::NoError,
                 qdcount: 2,
                 ancount: 0,
                 nscount: 0,
@@ -410,16 +654,17 @@ mod tests {
             },
             questions: vec![
                 Question {
-                    labels: vec!["www".to_string(), "example".to_string(), "com".to_string()],
-                    qtype: 1,
-                    qclass: 1,
+                    name: name("www.example.com"),
+                    qtype: RecordType::A,
+                    qclass: DnsClass::IN,
                 },
                 Question {
-                    labels: vec!["www".to_string(), "example".to_string(), "org".to_string()],
-                    qtype: 1,
-                    qclass: 1,
+                    name: name("www.example.org"),
+                    qtype: RecordType::A,
+                    qclass: DnsClass::IN,
                 },
             ],
+            edns: None,
         };
         let serialized = query.serialize();
         assert_eq!(serialized, SERIALIZED_DNS_QUERY_MULTIPLE_QUESTIONS);
@@ -427,47 +672,47 @@ mod tests {
 
     #[test]
     fn test_dns_query_deserialize() {
-        let query = DnsQuery::deserialize(&SERIALIZED_DNS_QUERY_SINGLE_QUESTION);
+        let query = DnsQuery::deserialize(&SERIALIZED_DNS_QUERY_SINGLE_QUESTION).unwrap();
         assert_eq!(query.header.id, 0x1234);
         assert_eq!(query.header.qr, 0);
-        assert_eq!(query.header.opcode, 0);
+        assert_eq!(query.header.opcode, Opcode::Query);
         assert_eq!(query.header.aa, 0);
         assert_eq!(query.header.tc, 0);
         assert_eq!(query.header.rd, 1);
         assert_eq!(query.header.ra, 0);
         assert_eq!(query.header.z, 0);
-        assert_eq!(query.header.rcode, 0);
+        assert_eq!(query.header.rcode, ResponseCode::NoError);
         assert_eq!(query.header.qdcount, 1);
         assert_eq!(query.header.ancount, 0);
         assert_eq!(query.header.nscount, 0);
         assert_eq!(query.header.arcount, 0);
-        assert_eq!(query.questions[0].labels, vec!["www".to_string(), "example".to_string(), "com".to_string()]);
-        assert_eq!(query.questions[0].qtype, 1);
-        assert_eq!(query.questions[0].qclass, 1);
+        assert_eq!(query.questions[0].name, name("www.example.com"));
+        assert_eq!(query.questions[0].qtype, RecordType::A);
+        assert_eq!(query.questions[0].qclass, DnsClass::IN);
     }
 
     #[test]
     fn test_dns_query_deserialize_multiple() {
-        let query = DnsQuery::deserialize(&SERIALIZED_DNS_QUERY_MULTIPLE_QUESTIONS);
+        let query = DnsQuery::deserialize(&SERIALIZED_DNS_QUERY_MULTIPLE_QUESTIONS).unwrap();
         assert_eq!(query.header.id, 0x1234);
         assert_eq!(query.header.qr, 0);
-        assert_eq!(query.header.opcode, 0);
+        assert_eq!(query.header.opcode, Opcode::Query);
         assert_eq!(query.header.aa, 0);
         assert_eq!(query.header.tc, 0);
         assert_eq!(query.header.rd, 1);
         assert_eq!(query.header.ra, 0);
         assert_eq!(query.header.z, 0);
-        assert_eq!(query.header.rcode, 0);
+        assert_eq!(query.header.rcode, ResponseCode::NoError);
         assert_eq!(query.header.qdcount, 2);
         assert_eq!(query.header.ancount, 0);
         assert_eq!(query.header.nscount, 0);
         assert_eq!(query.header.arcount, 0);
-        assert_eq!(query.questions[0].labels, vec!["www".to_string(), "example".to_string(), "com".to_string()]);
-        assert_eq!(query.questions[0].qtype, 1);
-        assert_eq!(query.questions[0].qclass, 1);
-        assert_eq!(query.questions[1].labels, vec!["www".to_string(), "example".to_string(), "org".to_string()]);
-        assert_eq!(query.questions[1].qtype, 1);
-        assert_eq!(query.questions[1].qclass, 1);
+        assert_eq!(query.questions[0].name, name("www.example.com"));
+        assert_eq!(query.questions[0].qtype, RecordType::A);
+        assert_eq!(query.questions[0].qclass, DnsClass::IN);
+        assert_eq!(query.questions[1].name, name("www.example.org"));
+        assert_eq!(query.questions[1].qtype, RecordType::A);
+        assert_eq!(query.questions[1].qclass, DnsClass::IN);
     }
 
     #[test]
@@ -476,64 +721,301 @@ mod tests {
             header: DNSHeader {
                 id: 0x1234,
                 qr: 1,
-                opcode: 0,
+                opcode: Opcode::Query,
                 aa: 0,
                 tc: 0,
                 rd: 1,
                 ra: 0,
                 z: 0,
-                rcode: 0,
+                rcode: ResponseCode::NoError,
                 qdcount: 1,
                 ancount: 1,
                 nscount: 0,
                 arcount: 0,
             },
             questions: vec![Question {
-                labels: vec!["www".to_string(), "example".to_string(), "com".to_string()],
-                qtype: 1,
-                qclass: 1,
+                name: name("www.example.com"),
+                qtype: RecordType::A,
+                qclass: DnsClass::IN,
             }],
             answers: vec![ResourceRecord {
-                name: vec!["www".to_string(), "example".to_string(), "com".to_string()],
-                rtype: 1,
-                class: 1,
+                name: name("www.example.com"),
+                rtype: RecordType::A,
+                class: DnsClass::IN,
                 ttl: 0,
                 rdlength: 4,
                 rdata: vec![127, 0, 0, 1],
             }],
+            authority: Vec::new(),
+            additional: Vec::new(),
+            edns: None,
         };
         let serialized = response.serialize();
-        assert_eq!(serialized, SERIALIZED_DNS_RESPONSE);
+        assert_eq!(serialized, SERIALIZED_DNS_RESPONSE_OWNER_COMPRESSED);
     }
 
     #[test]
     fn test_dns_response_deserialize() {
-        let response = DnsResponse::deserialize(&SERIALIZED_DNS_RESPONSE);
+        let response = DnsResponse::deserialize(&SERIALIZED_DNS_RESPONSE).unwrap();
         assert_eq!(response.header.id, 0x1234);
         assert_eq!(response.header.qr, 1);
-        assert_eq!(response.header.opcode, 0);
+        assert_eq!(response.header.opcode, Opcode::Query);
         assert_eq!(response.header.aa, 0);
         assert_eq!(response.header.tc, 0);
         assert_eq!(response.header.rd, 1);
         assert_eq!(response.header.ra, 0);
         assert_eq!(response.header.z, 0);
-        assert_eq!(response.header.rcode, 0);
+        assert_eq!(response.header.rcode, ResponseCode::NoError);
         assert_eq!(response.header.qdcount, 1);
         assert_eq!(response.header.ancount, 1);
         assert_eq!(response.header.nscount, 0);
         assert_eq!(response.header.arcount, 0);
-        assert_eq!(response.questions[0].labels, vec!["www".to_string(), "example".to_string(), "com".to_string()]);
-        assert_eq!(response.questions[0].qtype, 1);
-        assert_eq!(response.questions[0].qclass, 1);
-        assert_eq!(response.answers[0].name, vec!["www".to_string(), "example".to_string(), "com".to_string()]);
-        assert_eq!(response.answers[0].rtype, 1);
-        assert_eq!(response.answers[0].class, 1);
+        assert_eq!(response.questions[0].name, name("www.example.com"));
+        assert_eq!(response.questions[0].qtype, RecordType::A);
+        assert_eq!(response.questions[0].qclass, DnsClass::IN);
+        assert_eq!(response.answers[0].name, name("www.example.com"));
+        assert_eq!(response.answers[0].rtype, RecordType::A);
+        assert_eq!(response.answers[0].class, DnsClass::IN);
         assert_eq!(response.answers[0].ttl, 0);
         assert_eq!(response.answers[0].rdlength, 4);
         assert_eq!(response.answers[0].rdata, vec![127, 0, 0, 1]);
     }
-    
+
+    #[test]
+    fn test_dns_header_deserialize_truncated() {
+        let result = DNSHeader::deserialize(&[0x12, 0x34, 0x01]);
+        assert_eq!(result.unwrap_err(), ParseError::TruncatedHeader(3));
+    }
+
+    #[test]
+    fn test_question_deserialize_label_overrun() {
+        let buffer = [3, 119, 119, 119, 7, 101, 120, 97];
+        let result = Question::deserialize(&buffer, 0, 1);
+        assert_eq!(result.unwrap_err(), ParseError::LabelOverrun { offset: 4, len: 7 });
+    }
+
+    #[test]
+    fn test_question_deserialize_pointer_loop() {
+        let buffer = [3, 119, 119, 119, 0xC0, 16, 0, 1, 0, 1];
+        let result = Question::deserialize(&buffer, 0, 1);
+        assert_eq!(result.unwrap_err(), ParseError::PointerLoop(4));
+    }
+
+    #[test]
+    fn test_question_deserialize_nested_pointers() {
+        // "com" at 0, "example" + pointer to "com" at 5, "www" + pointer to "example.com" at 15.
+        let buffer = [3, 99, 111, 109, 0, 7, 101, 120, 97, 109, 112, 108, 101, 0xC0, 0, 3, 119, 119, 119, 0xC0, 5, 0, 1, 0, 1];
+        let (questions, pos) = Question::deserialize(&buffer, 15, 1).unwrap();
+        assert_eq!(questions[0].name, name("www.example.com"));
+        assert_eq!(questions[0].qtype, RecordType::A);
+        assert_eq!(questions[0].qclass, DnsClass::IN);
+        assert_eq!(pos, buffer.len());
+    }
+
+    #[test]
+    fn test_question_deserialize_pointer_chain_loop() {
+        // Two names pointing at each other; the second pointer jumps forward again.
+        let buffer = [1, 97, 0xC0, 5, 0, 1, 98, 0xC0, 0];
+        let result = Question::deserialize(&buffer, 5, 1);
+        assert_eq!(result.unwrap_err(), ParseError::PointerLoop(2));
+    }
+
+    #[test]
+    fn test_question_deserialize_name_too_long() {
+        let mut buffer = Vec::new();
+        for _ in 0..5 {
+            buffer.push(63);
+            buffer.extend_from_slice(&[b'a'; 63]);
+        }
+        buffer.extend_from_slice(&[0, 0, 1, 0, 1]);
+        let result = Question::deserialize(&buffer, 0, 1);
+        assert_eq!(result.unwrap_err(), ParseError::NameTooLong(0));
+    }
+
+    #[test]
+    fn test_dns_response_deserialize_compressed() {
+        let response = DnsResponse::deserialize(&SERIALIZED_DNS_RESPONSE_COMPRESSED).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].name, name("www.example.com"));
+        assert_eq!(response.answers[0].rtype, RecordType::CNAME);
+        assert_eq!(response.answers[0].ttl, 3600);
+        assert_eq!(response.answers[0].rdlength, 17);
+        assert_eq!(response.answers[0].rdata, [3, 119, 101, 98, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0]);
+    }
+
+    #[test]
+    fn test_dns_response_deserialize_multiple_answers() {
+        let response = DnsResponse::deserialize(&SERIALIZED_DNS_RESPONSE_MULTIPLE_ANSWERS).unwrap();
+        assert_eq!(response.answers.len(), 3);
+        assert_eq!(response.answers[0].rtype, RecordType::CNAME);
+        assert_eq!(response.answers[0].rdata, [3, 119, 101, 98, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0]);
+        assert_eq!(response.answers[1].name, name("web.example.com"));
+        assert_eq!(response.answers[1].rtype, RecordType::A);
+        assert_eq!(response.answers[1].rdlength, 4);
+        assert_eq!(response.answers[1].rdata, vec![93, 184, 216, 34]);
+        assert_eq!(response.answers[2].name, name("web.example.com"));
+        assert_eq!(response.answers[2].rdata, vec![93, 184, 216, 35]);
+    }
+
+    #[test]
+    fn test_resource_record_data() {
+        let response = DnsResponse::deserialize(&SERIALIZED_DNS_RESPONSE_MULTIPLE_ANSWERS).unwrap();
+        let web_example_com = name("web.example.com");
+        assert_eq!(response.answers[0].data().unwrap(), RData::CNAME(web_example_com.clone()));
+        assert_eq!(response.answers[1].data().unwrap(), RData::A(std::net::Ipv4Addr::new(93, 184, 216, 34)));
+
+        let record = ResourceRecord::new(web_example_com, DnsClass::IN, 3600, RData::A(std::net::Ipv4Addr::new(93, 184, 216, 35)));
+        assert_eq!(record.rtype, RecordType::A);
+        assert_eq!(record.rdlength, 4);
+        assert_eq!(record.rdata, vec![93, 184, 216, 35]);
+    }
+
+    #[test]
+    fn test_dns_response_deserialize_authority_and_additional() {
+        let response = DnsResponse::deserialize(&SERIALIZED_DNS_RESPONSE_DELEGATION).unwrap();
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(response.authority.len(), 1);
+        assert_eq!(response.authority[0].name, name("example.com"));
+        assert_eq!(response.authority[0].rtype, RecordType::NS);
+        assert_eq!(response.authority[0].rdata, [3, 110, 115, 49, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0]);
+        assert_eq!(response.additional.len(), 1);
+        assert_eq!(response.additional[0].name, name("ns1.example.com"));
+        assert_eq!(response.additional[0].rtype, RecordType::A);
+        assert_eq!(response.additional[0].rdata, vec![192, 0, 2, 53]);
+    }
+
+    #[test]
+    fn test_dns_response_serialize_authority_and_additional() {
+        let response = DnsResponse::deserialize(&SERIALIZED_DNS_RESPONSE_DELEGATION).unwrap();
+        let reparsed = DnsResponse::deserialize(&response.serialize()).unwrap();
+        assert_eq!(reparsed.header.nscount, 1);
+        assert_eq!(reparsed.header.arcount, 1);
+        assert_eq!(reparsed.authority[0].rdata, response.authority[0].rdata);
+        assert_eq!(reparsed.additional[0].name, response.additional[0].name);
+        assert_eq!(reparsed.additional[0].rdata, response.additional[0].rdata);
+    }
+
+    #[test]
+    fn test_dns_response_serialize_compressed() {
+        for packet in [
+            &SERIALIZED_DNS_RESPONSE_COMPRESSED[..],
+            &SERIALIZED_DNS_RESPONSE_MULTIPLE_ANSWERS[..],
+            &SERIALIZED_DNS_RESPONSE_DELEGATION[..],
+        ] {
+            let response = DnsResponse::deserialize(packet).unwrap();
+            assert_eq!(response.serialize(), packet);
+        }
+    }
+
+    #[test]
+    fn test_dns_query_edns() {
+        let query = DnsQuery::deserialize(&SERIALIZED_DNS_QUERY_EDNS).unwrap();
+        assert_eq!(query.header.arcount, 1);
+        let edns = query.edns.as_ref().unwrap();
+        assert_eq!(edns.udp_payload_size, 4096);
+        assert!(edns.dnssec_ok);
+        assert_eq!(edns.max_payload(), 4096);
+        assert_eq!(query.serialize(), SERIALIZED_DNS_QUERY_EDNS);
+    }
+
+    #[test]
+    fn test_dns_query_serialize_drops_other_records() {
+        let mut buffer = SERIALIZED_DNS_QUERY_EDNS.to_vec();
+        buffer[11] = 2;
+        buffer.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 0, 0, 4, 127, 0, 0, 1]);
+        let query = DnsQuery::deserialize(&buffer).unwrap();
+        assert_eq!(query.serialize(), SERIALIZED_DNS_QUERY_EDNS);
+    }
+
+    #[test]
+    fn test_dns_response_edns() {
+        let mut response = DnsResponse::deserialize(&SERIALIZED_DNS_RESPONSE_DELEGATION).unwrap();
+        let mut edns = Edns::new(1232);
+        edns.extended_rcode = 1;
+        response.edns = Some(edns.clone());
+        let reparsed = DnsResponse::deserialize(&response.serialize()).unwrap();
+        assert_eq!(reparsed.header.arcount, 2);
+        assert_eq!(reparsed.additional.len(), 1);
+        assert_eq!(reparsed.edns, Some(edns));
+        assert_eq!(reparsed.response_code(), ResponseCode::BadVers);
+    }
+
+    #[test]
+    fn test_dns_response_serialize_truncated() {
+        let response = DnsResponse::deserialize(&SERIALIZED_DNS_RESPONSE_MULTIPLE_ANSWERS).unwrap();
+        assert_eq!(response.serialize_truncated(512), SERIALIZED_DNS_RESPONSE_MULTIPLE_ANSWERS);
+
+        let truncated = DnsResponse::deserialize(&response.serialize_truncated(64)).unwrap();
+        assert_eq!(truncated.header.tc, 1);
+        assert_eq!(truncated.header.ancount, 0);
+        assert_eq!(truncated.questions[0].name, name("www.example.com"));
+    }
+
+    #[test]
+    fn test_dns_response_deserialize_bad_additional_count() {
+        let mut buffer = SERIALIZED_DNS_RESPONSE_DELEGATION;
+        buffer[11] = 2;
+        let result = DnsResponse::deserialize(&buffer);
+        assert_eq!(
+            result.unwrap_err(),
+            ParseError::BadCount {
+                section: "additional",
+                declared: 2,
+                found: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_resource_record_deserialize_rdlength_overrun() {
+        let mut buffer = SERIALIZED_DNS_RESPONSE;
+        buffer[59] = 5;
+        let result = DnsResponse::deserialize(&buffer);
+        assert_eq!(result.unwrap_err(), ParseError::UnexpectedEnd(60));
+    }
+
+    #[test]
+    fn test_resource_record_deserialize_name_overruns_rdata() {
+        let mut buffer = SERIALIZED_DNS_RESPONSE_COMPRESSED;
+        buffer[44] = 5;
+        let result = DnsResponse::deserialize(&buffer);
+        assert_eq!(result.unwrap_err(), ParseError::UnexpectedEnd(49));
+    }
+
+    #[test]
+    fn test_dns_query_deserialize_bad_count() {
+        let mut buffer = SERIALIZED_DNS_QUERY_SINGLE_QUESTION;
+        buffer[5] = 2;
+        let result = DnsQuery::deserialize(&buffer);
+        assert_eq!(
+            result.unwrap_err(),
+            ParseError::BadCount {
+                section: "question",
+                declared: 2,
+                found: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_dns_response_deserialize_truncated_record() {
+        let result = DnsResponse::deserialize(&SERIALIZED_DNS_RESPONSE[..58]);
+        assert!(matches!(result, Err(ParseError::UnexpectedEnd(_))));
+    }
+
+    #[test]
+    fn test_dns_response_format_error() {
+        let response = DnsResponse::format_error(&[0x12, 0x34, 0x01, 0xFF]).unwrap();
+        assert_eq!(response.serialize(), [0x12, 0x34, 0x81, 0x81, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(DnsResponse::format_error(&[0x12]).is_none());
+    }
+
     const SERIALIZED_DNS_RESPONSE: [u8; 64] = [0x12, 0x34, 0x81, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 4, 127, 0, 0, 1];
+    const SERIALIZED_DNS_RESPONSE_OWNER_COMPRESSED: [u8; 49] = [0x12, 0x34, 0x81, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 0, 0, 4, 127, 0, 0, 1];
+    const SERIALIZED_DNS_RESPONSE_COMPRESSED: [u8; 51] = [0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 5, 0, 1, 0xC0, 12, 0, 5, 0, 1, 0, 0, 0x0E, 0x10, 0, 6, 3, 119, 101, 98, 0xC0, 16];
+    const SERIALIZED_DNS_RESPONSE_MULTIPLE_ANSWERS: [u8; 83] = [0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0xC0, 12, 0, 5, 0, 1, 0, 0, 0x0E, 0x10, 0, 6, 3, 119, 101, 98, 0xC0, 16, 0xC0, 45, 0, 1, 0, 1, 0, 0, 0x0E, 0x10, 0, 4, 93, 184, 216, 34, 0xC0, 45, 0, 1, 0, 1, 0, 0, 0x0E, 0x10, 0, 4, 93, 184, 216, 35];
+    const SERIALIZED_DNS_RESPONSE_DELEGATION: [u8; 67] = [0x12, 0x34, 0x81, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0xC0, 16, 0, 2, 0, 1, 0, 2, 0xA3, 0, 0, 6, 3, 110, 115, 49, 0xC0, 16, 0xC0, 45, 0, 1, 0, 1, 0, 2, 0xA3, 0, 0, 4, 192, 0, 2, 53];
+    const SERIALIZED_DNS_QUERY_EDNS: [u8; 44] = [0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 0, 41, 0x10, 0x00, 0, 0, 0x80, 0, 0, 0];
     const SERIALIZED_DNS_QUERY_SINGLE_QUESTION: [u8; 33] = [0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1];
     const SERIALIZED_DNS_QUERY_MULTIPLE_QUESTIONS: [u8; 54] = [0x12, 0x34, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 111, 114, 103, 0, 0, 1, 0, 1];
 }
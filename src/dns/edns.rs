@@ -0,0 +1,173 @@
+use super::{
+    read_slice, read_u16, DnsClass, DomainName, ParseError, RecordType, ResourceRecord,
+    ResponseCode,
+};
+
+/// The smallest UDP payload any EDNS speaker must accept, and the limit for everyone else.
+pub const MIN_UDP_PAYLOAD_SIZE: u16 = 512;
+/// What we advertise ourselves: the DNS Flag Day 2020 size, which avoids IP fragmentation.
+pub const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// A single EDNS option, kept as its raw code and data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// The contents of an OPT pseudo-record (RFC 6891), which overloads the fields of a resource
+/// record to carry message-wide extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    /// The upper 8 bits of the 12-bit response code.
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    pub fn new(udp_payload_size: u16) -> Edns {
+        return Edns {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        };
+    }
+
+    /// Removes the OPT record from an additional section, if there is one, and decodes it.
+    pub fn take_from(additional: &mut Vec<ResourceRecord>) -> Result<Option<Edns>, ParseError> {
+        let mut opts = additional
+            .iter()
+            .filter(|record| record.rtype == RecordType::OPT);
+        if opts.nth(1).is_some() {
+            return Err(ParseError::DuplicateOpt);
+        }
+        return match additional
+            .iter()
+            .position(|record| record.rtype == RecordType::OPT)
+        {
+            Some(index) => Ok(Some(Edns::from_record(&additional.remove(index))?)),
+            None => Ok(None),
+        };
+    }
+
+    pub fn from_record(record: &ResourceRecord) -> Result<Edns, ParseError> {
+        let mut options = Vec::new();
+        let mut pos = 0;
+        while pos < record.rdata.len() {
+            let code = read_u16(&record.rdata, pos)?;
+            let len = read_u16(&record.rdata, pos + 2)? as usize;
+            let data = read_slice(&record.rdata, pos + 4, len)?.to_vec();
+            options.push(EdnsOption { code, data });
+            pos += 4 + len;
+        }
+        return Ok(Edns {
+            udp_payload_size: u16::from(record.class),
+            extended_rcode: (record.ttl >> 24) as u8,
+            version: (record.ttl >> 16) as u8,
+            dnssec_ok: record.ttl & 0x8000 != 0,
+            options,
+        });
+    }
+
+    pub fn to_record(&self) -> ResourceRecord {
+        let mut rdata = Vec::new();
+        for option in &self.options {
+            rdata.extend_from_slice(&option.code.to_be_bytes());
+            rdata.extend_from_slice(&(option.data.len() as u16).to_be_bytes());
+            rdata.extend_from_slice(&option.data);
+        }
+        let ttl = (self.extended_rcode as u32) << 24
+            | (self.version as u32) << 16
+            | if self.dnssec_ok { 0x8000 } else { 0 };
+        return ResourceRecord {
+            name: DomainName::root(),
+            rtype: RecordType::OPT,
+            class: DnsClass::from(self.udp_payload_size),
+            ttl,
+            rdlength: rdata.len() as u16,
+            rdata,
+        };
+    }
+
+    /// The largest response the sender will take over UDP. Advertised sizes below 512 are
+    /// treated as 512, as RFC 6891 requires.
+    pub fn max_payload(&self) -> usize {
+        return self.udp_payload_size.max(MIN_UDP_PAYLOAD_SIZE) as usize;
+    }
+
+    /// Combines the extended bits with the 4 bits from the header into the full response code.
+    pub fn response_code(&self, header_rcode: ResponseCode) -> ResponseCode {
+        let low = u16::from(header_rcode) & 0b1111;
+        return ResponseCode::from((self.extended_rcode as u16) << 4 | low);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edns_round_trip() {
+        let edns = Edns {
+            udp_payload_size: 1232,
+            extended_rcode: 1,
+            version: 0,
+            dnssec_ok: true,
+            options: vec![EdnsOption {
+                code: 10,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            }],
+        };
+        let record = edns.to_record();
+        assert_eq!(
+            record.serialize(),
+            [0, 0, 41, 0x04, 0xD0, 1, 0, 0x80, 0, 0, 12, 0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8]
+        );
+        assert_eq!(Edns::from_record(&record).unwrap(), edns);
+    }
+
+    #[test]
+    fn test_edns_response_code() {
+        let mut edns = Edns::new(4096);
+        edns.extended_rcode = 1;
+        assert_eq!(
+            edns.response_code(ResponseCode::NoError),
+            ResponseCode::BadVers
+        );
+    }
+
+    #[test]
+    fn test_edns_max_payload() {
+        assert_eq!(Edns::new(4096).max_payload(), 4096);
+        assert_eq!(Edns::new(100).max_payload(), 512);
+    }
+
+    #[test]
+    fn test_edns_take_from() {
+        let mut additional = vec![Edns::new(4096).to_record()];
+        let edns = Edns::take_from(&mut additional).unwrap();
+        assert_eq!(edns, Some(Edns::new(4096)));
+        assert!(additional.is_empty());
+
+        let mut additional = vec![Edns::new(4096).to_record(), Edns::new(512).to_record()];
+        assert_eq!(
+            Edns::take_from(&mut additional),
+            Err(ParseError::DuplicateOpt)
+        );
+    }
+
+    #[test]
+    fn test_edns_option_overrun() {
+        let mut record = Edns::new(4096).to_record();
+        record.rdata = vec![0, 10, 0, 8, 1, 2];
+        assert!(matches!(
+            Edns::from_record(&record),
+            Err(ParseError::UnexpectedEnd(_))
+        ));
+    }
+}
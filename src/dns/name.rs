@@ -0,0 +1,398 @@
+use super::{read_slice, read_u16, read_u8, ParseError};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use thiserror::Error;
+
+pub const MAX_LABEL_LENGTH: usize = 63;
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// Reasons a domain name can be rejected when built from text or labels.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum NameError {
+    #[error("name contains an empty label")]
+    EmptyLabel,
+    #[error("label is {0} bytes long, the limit is 63")]
+    LabelTooLong(usize),
+    #[error("name is {0} bytes long on the wire, the limit is 255")]
+    NameTooLong(usize),
+    #[error("invalid escape sequence in {0:?}")]
+    BadEscape(String),
+}
+
+/// A domain name, held as the raw bytes of its labels without the terminating root label.
+///
+/// Comparison and hashing ignore ASCII case, as DNS does, so names can be used directly as keys.
+#[derive(Clone, Default)]
+pub struct DomainName {
+    labels: Vec<Vec<u8>>,
+}
+
+impl DomainName {
+    pub fn root() -> DomainName {
+        return DomainName::default();
+    }
+
+    pub fn from_labels(labels: Vec<Vec<u8>>) -> Result<DomainName, NameError> {
+        let mut wire_length = 1;
+        for label in &labels {
+            if label.is_empty() {
+                return Err(NameError::EmptyLabel);
+            }
+            if label.len() > MAX_LABEL_LENGTH {
+                return Err(NameError::LabelTooLong(label.len()));
+            }
+            wire_length += label.len() + 1;
+        }
+        if wire_length > MAX_NAME_LENGTH {
+            return Err(NameError::NameTooLong(wire_length));
+        }
+        return Ok(DomainName { labels });
+    }
+
+    #[allow(dead_code)]
+    pub fn labels(&self) -> &[Vec<u8>] {
+        return &self.labels;
+    }
+
+    pub fn is_root(&self) -> bool {
+        return self.labels.is_empty();
+    }
+
+    /// Length of the name in uncompressed wire format, including the root label.
+    pub fn wire_length(&self) -> usize {
+        return self
+            .labels
+            .iter()
+            .map(|label| label.len() + 1)
+            .sum::<usize>()
+            + 1;
+    }
+
+    /// Whether this name is `other` or lies below it.
+    pub fn is_subdomain_of(&self, other: &DomainName) -> bool {
+        if other.labels.len() > self.labels.len() {
+            return false;
+        }
+        let offset = self.labels.len() - other.labels.len();
+        return self.labels[offset..]
+            .iter()
+            .zip(&other.labels)
+            .all(|(ours, theirs)| ours.eq_ignore_ascii_case(theirs));
+    }
+
+    /// The name with its leftmost label removed, or `None` for the root.
+    #[allow(dead_code)]
+    pub fn parent(&self) -> Option<DomainName> {
+        if self.is_root() {
+            return None;
+        }
+        return Some(DomainName {
+            labels: self.labels[1..].to_vec(),
+        });
+    }
+
+    /// Every ancestor of the name, nearest first, ending with the root.
+    #[allow(dead_code)]
+    pub fn parents(&self) -> impl Iterator<Item = DomainName> + '_ {
+        return (1..=self.labels.len()).map(move |skip| DomainName {
+            labels: self.labels[skip..].to_vec(),
+        });
+    }
+
+    /// Reads a name starting at `pos`, following compression pointers against the full message
+    /// in `buffer`. Returns the name and the offset just past it as it appears at `pos`, which is
+    /// right after the first pointer if the name was compressed.
+    ///
+    /// Every pointer has to land before the start of the run of labels it terminates. Compressors
+    /// only ever point back at names they have already written, and the rule guarantees we make
+    /// progress towards the start of the message, so a pointer loop can't send us round forever.
+    pub fn deserialize(buffer: &[u8], pos: usize) -> Result<(DomainName, usize), ParseError> {
+        let start = pos;
+        let mut pos = pos;
+        let mut segment_start = pos;
+        let mut end = None;
+        let mut name_length = 1;
+        let mut labels = Vec::new();
+        loop {
+            let len = read_u8(buffer, pos)? as usize;
+            match len & 0xC0 {
+                0xC0 => {
+                    let target = (read_u16(buffer, pos)? & 0x3FFF) as usize;
+                    if target >= segment_start {
+                        return Err(ParseError::PointerLoop(pos));
+                    }
+                    end.get_or_insert(pos + 2);
+                    pos = target;
+                    segment_start = target;
+                }
+                0x00 => {
+                    if len == 0 {
+                        return Ok((DomainName { labels }, end.unwrap_or(pos + 1)));
+                    }
+                    let label = read_slice(buffer, pos + 1, len)
+                        .map_err(|_| ParseError::LabelOverrun { offset: pos, len })?;
+                    name_length += len + 1;
+                    if name_length > MAX_NAME_LENGTH {
+                        return Err(ParseError::NameTooLong(start));
+                    }
+                    labels.push(label.to_vec());
+                    pos += len + 1;
+                }
+                _ => return Err(ParseError::InvalidLabelType(len as u8)),
+            }
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.wire_length());
+        for label in &self.labels {
+            buffer.push(label.len() as u8);
+            buffer.extend_from_slice(label);
+        }
+        buffer.push(0);
+        return buffer;
+    }
+
+    /// Appends the name to `buffer`, which holds the message written so far, replacing the
+    /// longest suffix recorded in `names` with a pointer to it. Suffixes written out in full are
+    /// recorded in turn, as long as their offset still fits in a pointer.
+    pub fn serialize_compressed(
+        &self,
+        buffer: &mut Vec<u8>,
+        names: &mut HashMap<DomainName, usize>,
+    ) {
+        for (i, label) in self.labels.iter().enumerate() {
+            let suffix = DomainName {
+                labels: self.labels[i..].to_vec(),
+            };
+            if let Some(&offset) = names.get(&suffix) {
+                buffer.extend_from_slice(&(0xC000 | offset as u16).to_be_bytes());
+                return;
+            }
+            if buffer.len() < 0x4000 {
+                names.insert(suffix, buffer.len());
+            }
+            buffer.push(label.len() as u8);
+            buffer.extend_from_slice(label);
+        }
+        buffer.push(0);
+    }
+}
+
+impl PartialEq for DomainName {
+    fn eq(&self, other: &Self) -> bool {
+        return self.labels.len() == other.labels.len() && self.is_subdomain_of(other);
+    }
+}
+
+impl Eq for DomainName {}
+
+impl Hash for DomainName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for label in &self.labels {
+            state.write_u8(label.len() as u8);
+            for byte in label {
+                state.write_u8(byte.to_ascii_lowercase());
+            }
+        }
+        state.write_u8(0);
+    }
+}
+
+/// Writes the name in presentation format, fully qualified with a trailing dot. Dots and
+/// backslashes inside labels are escaped with a backslash, and anything that isn't printable
+/// ASCII as `\DDD`.
+impl Display for DomainName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_root() {
+            return write!(f, ".");
+        }
+        for label in &self.labels {
+            for &byte in label {
+                match byte {
+                    b'.' | b'\\' => write!(f, "\\{}", byte as char)?,
+                    0x21..=0x7E => write!(f, "{}", byte as char)?,
+                    _ => write!(f, "\\{:03}", byte)?,
+                }
+            }
+            write!(f, ".")?;
+        }
+        return Ok(());
+    }
+}
+
+impl Debug for DomainName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "DomainName({})", self);
+    }
+}
+
+/// Parses a name in presentation format. The trailing dot is optional, and `.` on its own is
+/// the root.
+impl FromStr for DomainName {
+    type Err = NameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "." {
+            return Ok(DomainName::root());
+        }
+        let mut labels = Vec::new();
+        let mut label = Vec::new();
+        let mut bytes = s.bytes();
+        let bad_escape = || NameError::BadEscape(s.to_owned());
+        while let Some(byte) = bytes.next() {
+            match byte {
+                b'.' => {
+                    if label.is_empty() {
+                        return Err(NameError::EmptyLabel);
+                    }
+                    labels.push(std::mem::take(&mut label));
+                }
+                b'\\' => {
+                    let escaped = bytes.next().ok_or_else(bad_escape)?;
+                    if escaped.is_ascii_digit() {
+                        let digits = [
+                            escaped,
+                            bytes.next().ok_or_else(bad_escape)?,
+                            bytes.next().ok_or_else(bad_escape)?,
+                        ];
+                        let value = std::str::from_utf8(&digits)
+                            .ok()
+                            .and_then(|digits| digits.parse::<u8>().ok())
+                            .ok_or_else(bad_escape)?;
+                        label.push(value);
+                    } else {
+                        label.push(escaped);
+                    }
+                }
+                _ => label.push(byte),
+            }
+        }
+        if !label.is_empty() {
+            labels.push(label);
+        } else if labels.is_empty() {
+            return Err(NameError::EmptyLabel);
+        }
+        return DomainName::from_labels(labels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+
+    fn name(s: &str) -> DomainName {
+        return s.parse().unwrap();
+    }
+
+    fn hash(name: &DomainName) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        return hasher.finish();
+    }
+
+    #[test]
+    fn test_domain_name_from_str() {
+        let parsed = name("www.example.com");
+        assert_eq!(
+            parsed.labels(),
+            [b"www".to_vec(), b"example".to_vec(), b"com".to_vec()]
+        );
+        assert_eq!(name("www.example.com."), parsed);
+        assert!(name(".").is_root());
+    }
+
+    #[test]
+    fn test_domain_name_escapes() {
+        let parsed = name("a\\.b\\\\c\\000.example");
+        assert_eq!(parsed.labels()[0], b"a.b\\c\0".to_vec());
+        assert_eq!(parsed.to_string(), "a\\.b\\\\c\\000.example.");
+        assert_eq!(parsed.to_string().parse::<DomainName>().unwrap(), parsed);
+    }
+
+    #[test]
+    fn test_domain_name_invalid() {
+        assert_eq!("a..b".parse::<DomainName>(), Err(NameError::EmptyLabel));
+        assert_eq!("".parse::<DomainName>(), Err(NameError::EmptyLabel));
+        assert_eq!(
+            "a\\25".parse::<DomainName>(),
+            Err(NameError::BadEscape("a\\25".to_string()))
+        );
+        assert_eq!(
+            "a\\256".parse::<DomainName>(),
+            Err(NameError::BadEscape("a\\256".to_string()))
+        );
+        assert_eq!(
+            "a".repeat(64).parse::<DomainName>(),
+            Err(NameError::LabelTooLong(64))
+        );
+        let long = vec!["a".repeat(63); 4].join(".");
+        assert_eq!(long.parse::<DomainName>(), Err(NameError::NameTooLong(257)));
+    }
+
+    #[test]
+    fn test_domain_name_case_insensitive() {
+        assert_eq!(name("WWW.Example.COM"), name("www.example.com"));
+        assert_eq!(
+            hash(&name("WWW.Example.COM")),
+            hash(&name("www.example.com"))
+        );
+        assert_ne!(name("www.example.com"), name("example.com"));
+        assert_eq!(name("WWW.Example.COM").to_string(), "WWW.Example.COM.");
+    }
+
+    #[test]
+    fn test_domain_name_is_subdomain_of() {
+        assert!(name("www.Example.com").is_subdomain_of(&name("example.COM")));
+        assert!(name("example.com").is_subdomain_of(&name("example.com")));
+        assert!(name("example.com").is_subdomain_of(&DomainName::root()));
+        assert!(!name("example.com").is_subdomain_of(&name("www.example.com")));
+        assert!(!name("badexample.com").is_subdomain_of(&name("example.com")));
+    }
+
+    #[test]
+    fn test_domain_name_parents() {
+        let www = name("www.example.com");
+        assert_eq!(www.parent(), Some(name("example.com")));
+        assert_eq!(DomainName::root().parent(), None);
+        let parents: Vec<DomainName> = www.parents().collect();
+        assert_eq!(
+            parents,
+            vec![name("example.com"), name("com"), DomainName::root()]
+        );
+    }
+
+    #[test]
+    fn test_domain_name_serialize_compressed() {
+        let mut buffer = vec![0; 12];
+        let mut names = HashMap::new();
+        name("www.example.com").serialize_compressed(&mut buffer, &mut names);
+        name("mail.EXAMPLE.com").serialize_compressed(&mut buffer, &mut names);
+        name("www.example.com").serialize_compressed(&mut buffer, &mut names);
+        name("example.org").serialize_compressed(&mut buffer, &mut names);
+        DomainName::root().serialize_compressed(&mut buffer, &mut names);
+        assert_eq!(
+            buffer[12..],
+            [
+                3, 119, 119, 119, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 4, 109,
+                97, 105, 108, 0xC0, 16, 0xC0, 12, 7, 101, 120, 97, 109, 112, 108, 101, 3, 111, 114,
+                103, 0, 0
+            ]
+        );
+        let (decoded, _) = DomainName::deserialize(&buffer, 29).unwrap();
+        assert_eq!(decoded, name("mail.example.com"));
+    }
+
+    #[test]
+    fn test_domain_name_binary_label() {
+        let buffer = [2, 0xFF, 0xC3, 0];
+        let (parsed, pos) = DomainName::deserialize(&buffer, 0).unwrap();
+        assert_eq!(parsed.labels()[0], vec![0xFF, 0xC3]);
+        assert_eq!(pos, 4);
+        assert_eq!(parsed.serialize(), buffer);
+        assert_eq!(parsed.to_string(), "\\255\\195.");
+    }
+}
@@ -0,0 +1,445 @@
+use super::{read_slice, read_u16, read_u32, read_u8, DomainName, ParseError, RecordType};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// The contents of a resource record, decoded according to its type.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    NS(DomainName),
+    CNAME(DomainName),
+    PTR(DomainName),
+    MX {
+        preference: u16,
+        exchange: DomainName,
+    },
+    TXT(Vec<Vec<u8>>),
+    SOA {
+        mname: DomainName,
+        rname: DomainName,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: DomainName,
+    },
+    CAA {
+        flags: u8,
+        /// Raw bytes, so that a tag that isn't ASCII still goes back out unchanged.
+        tag: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Unknown(RecordType, Vec<u8>),
+}
+
+impl RData {
+    /// Decodes RDATA of type `rtype` starting at `pos` and running to the end of `buffer`.
+    /// Callers pass the message truncated to the end of the record, so that embedded names can
+    /// still follow compression pointers back into the rest of the message.
+    pub fn deserialize(buffer: &[u8], pos: usize, rtype: RecordType) -> Result<RData, ParseError> {
+        let bad_length = ParseError::BadRdataLength {
+            rtype,
+            len: buffer.len().saturating_sub(pos),
+        };
+        let (rdata, end) = match rtype {
+            RecordType::A => {
+                let octets: [u8; 4] = read_slice(buffer, pos, 4)?.try_into().unwrap();
+                (RData::A(Ipv4Addr::from(octets)), pos + 4)
+            }
+            RecordType::AAAA => {
+                let octets: [u8; 16] = read_slice(buffer, pos, 16)?.try_into().unwrap();
+                (RData::AAAA(Ipv6Addr::from(octets)), pos + 16)
+            }
+            RecordType::NS => {
+                let (name, end) = DomainName::deserialize(buffer, pos)?;
+                (RData::NS(name), end)
+            }
+            RecordType::CNAME => {
+                let (name, end) = DomainName::deserialize(buffer, pos)?;
+                (RData::CNAME(name), end)
+            }
+            RecordType::PTR => {
+                let (name, end) = DomainName::deserialize(buffer, pos)?;
+                (RData::PTR(name), end)
+            }
+            RecordType::MX => {
+                let preference = read_u16(buffer, pos)?;
+                let (exchange, end) = DomainName::deserialize(buffer, pos + 2)?;
+                (
+                    RData::MX {
+                        preference,
+                        exchange,
+                    },
+                    end,
+                )
+            }
+            RecordType::TXT => {
+                let mut strings = Vec::new();
+                let mut pos = pos;
+                while pos < buffer.len() {
+                    let len = read_u8(buffer, pos)? as usize;
+                    strings.push(read_slice(buffer, pos + 1, len)?.to_vec());
+                    pos += len + 1;
+                }
+                (RData::TXT(strings), pos)
+            }
+            RecordType::SOA => {
+                let (mname, next) = DomainName::deserialize(buffer, pos)?;
+                let (rname, next) = DomainName::deserialize(buffer, next)?;
+                let rdata = RData::SOA {
+                    mname,
+                    rname,
+                    serial: read_u32(buffer, next)?,
+                    refresh: read_u32(buffer, next + 4)?,
+                    retry: read_u32(buffer, next + 8)?,
+                    expire: read_u32(buffer, next + 12)?,
+                    minimum: read_u32(buffer, next + 16)?,
+                };
+                (rdata, next + 20)
+            }
+            RecordType::SRV => {
+                let priority = read_u16(buffer, pos)?;
+                let weight = read_u16(buffer, pos + 2)?;
+                let port = read_u16(buffer, pos + 4)?;
+                let (target, end) = DomainName::deserialize(buffer, pos + 6)?;
+                let rdata = RData::SRV {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                };
+                (rdata, end)
+            }
+            RecordType::CAA => {
+                let flags = read_u8(buffer, pos)?;
+                let tag_length = read_u8(buffer, pos + 1)? as usize;
+                let tag = read_slice(buffer, pos + 2, tag_length)?.to_vec();
+                let value = buffer[pos + 2 + tag_length..].to_vec();
+                let rdata = RData::CAA { flags, tag, value };
+                (rdata, buffer.len())
+            }
+            _ => (
+                RData::Unknown(rtype, buffer[pos.min(buffer.len())..].to_vec()),
+                buffer.len(),
+            ),
+        };
+        if end != buffer.len() {
+            return Err(bad_length);
+        }
+        return Ok(rdata);
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        match self {
+            RData::A(address) => buffer.extend_from_slice(&address.octets()),
+            RData::AAAA(address) => buffer.extend_from_slice(&address.octets()),
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => {
+                buffer.extend_from_slice(&name.serialize())
+            }
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                buffer.extend_from_slice(&preference.to_be_bytes());
+                buffer.extend_from_slice(&exchange.serialize());
+            }
+            RData::TXT(strings) => {
+                for string in strings {
+                    // A character-string holds at most 255 bytes, so longer strings go out as
+                    // several, which readers of TXT records join back together anyway.
+                    if string.is_empty() {
+                        buffer.push(0);
+                    }
+                    for chunk in string.chunks(u8::MAX as usize) {
+                        buffer.push(chunk.len() as u8);
+                        buffer.extend_from_slice(chunk);
+                    }
+                }
+            }
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                buffer.extend_from_slice(&mname.serialize());
+                buffer.extend_from_slice(&rname.serialize());
+                for value in [serial, refresh, retry, expire, minimum] {
+                    buffer.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                buffer.extend_from_slice(&priority.to_be_bytes());
+                buffer.extend_from_slice(&weight.to_be_bytes());
+                buffer.extend_from_slice(&port.to_be_bytes());
+                buffer.extend_from_slice(&target.serialize());
+            }
+            RData::CAA { flags, tag, value } => {
+                buffer.push(*flags);
+                buffer.push(tag.len() as u8);
+                buffer.extend_from_slice(tag);
+                buffer.extend_from_slice(value);
+            }
+            RData::Unknown(_, data) => buffer.extend_from_slice(data),
+        }
+        return buffer;
+    }
+
+    /// Appends the data to `buffer`, which holds the message written so far, compressing
+    /// embedded names where RFC 3597 allows it. That is only the types from RFC 1035; names in
+    /// newer types such as SRV always go out in full.
+    pub fn serialize_compressed(
+        &self,
+        buffer: &mut Vec<u8>,
+        names: &mut HashMap<DomainName, usize>,
+    ) {
+        match self {
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => {
+                name.serialize_compressed(buffer, names)
+            }
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                buffer.extend_from_slice(&preference.to_be_bytes());
+                exchange.serialize_compressed(buffer, names);
+            }
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                mname.serialize_compressed(buffer, names);
+                rname.serialize_compressed(buffer, names);
+                for value in [serial, refresh, retry, expire, minimum] {
+                    buffer.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+            _ => buffer.extend_from_slice(&self.serialize()),
+        }
+    }
+
+    /// The record type this data belongs to.
+    #[allow(dead_code)]
+    pub fn rtype(&self) -> RecordType {
+        return match self {
+            RData::A(_) => RecordType::A,
+            RData::AAAA(_) => RecordType::AAAA,
+            RData::NS(_) => RecordType::NS,
+            RData::CNAME(_) => RecordType::CNAME,
+            RData::PTR(_) => RecordType::PTR,
+            RData::MX { .. } => RecordType::MX,
+            RData::TXT(_) => RecordType::TXT,
+            RData::SOA { .. } => RecordType::SOA,
+            RData::SRV { .. } => RecordType::SRV,
+            RData::CAA { .. } => RecordType::CAA,
+            RData::Unknown(rtype, _) => *rtype,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> DomainName {
+        return s.parse().unwrap();
+    }
+
+    fn assert_round_trip(rdata: RData, serialized: &[u8]) {
+        assert_eq!(rdata.serialize(), serialized);
+        assert_eq!(
+            RData::deserialize(serialized, 0, rdata.rtype()).unwrap(),
+            rdata
+        );
+    }
+
+    #[test]
+    fn test_rdata_a() {
+        assert_round_trip(RData::A(Ipv4Addr::new(127, 0, 0, 1)), &[127, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_rdata_aaaa() {
+        assert_round_trip(
+            RData::AAAA(Ipv6Addr::LOCALHOST),
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+        );
+    }
+
+    #[test]
+    fn test_rdata_cname() {
+        assert_round_trip(
+            RData::CNAME(name("example.com")),
+            &[7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0],
+        );
+    }
+
+    #[test]
+    fn test_rdata_mx() {
+        assert_round_trip(
+            RData::MX {
+                preference: 10,
+                exchange: name("example.com"),
+            },
+            &[
+                0, 10, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_rdata_txt() {
+        assert_round_trip(
+            RData::TXT(vec![b"v=spf1".to_vec(), b"".to_vec()]),
+            &[6, 118, 61, 115, 112, 102, 49, 0],
+        );
+    }
+
+    #[test]
+    fn test_rdata_txt_long_string() {
+        let long = RData::TXT(vec![vec![b'a'; 300]]);
+        let serialized = long.serialize();
+        assert_eq!(serialized.len(), 302);
+        assert_eq!((serialized[0], serialized[256]), (255, 45));
+        assert_eq!(
+            RData::deserialize(&serialized, 0, RecordType::TXT).unwrap(),
+            RData::TXT(vec![vec![b'a'; 255], vec![b'a'; 45]])
+        );
+    }
+
+    #[test]
+    fn test_rdata_soa() {
+        assert_round_trip(
+            RData::SOA {
+                mname: name("ns"),
+                rname: name("admin"),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            },
+            &[
+                2, 110, 115, 0, 5, 97, 100, 109, 105, 110, 0, 0, 0, 0, 1, 0, 0, 0x1C, 0x20, 0, 0,
+                0x0E, 0x10, 0, 0x12, 0x75, 0, 0, 0, 0x01, 0x2C,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_rdata_srv() {
+        assert_round_trip(
+            RData::SRV {
+                priority: 1,
+                weight: 5,
+                port: 5060,
+                target: name("example.com"),
+            },
+            &[
+                0, 1, 0, 5, 0x13, 0xC4, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_rdata_caa() {
+        assert_round_trip(
+            RData::CAA {
+                flags: 0,
+                tag: b"issue".to_vec(),
+                value: b"ca.example".to_vec(),
+            },
+            &[
+                0, 5, 105, 115, 115, 117, 101, 99, 97, 46, 101, 120, 97, 109, 112, 108, 101,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_rdata_caa_binary_tag() {
+        assert_round_trip(
+            RData::CAA {
+                flags: 128,
+                tag: vec![0xFF, b'x'],
+                value: vec![0xFE],
+            },
+            &[128, 2, 0xFF, b'x', 0xFE],
+        );
+    }
+
+    #[test]
+    fn test_rdata_unknown() {
+        assert_round_trip(
+            RData::Unknown(RecordType::Unknown(65280), vec![1, 2, 3]),
+            &[1, 2, 3],
+        );
+    }
+
+    #[test]
+    fn test_rdata_serialize_compressed() {
+        let mut buffer = name("example.com").serialize();
+        let mut names = HashMap::new();
+        names.insert(name("example.com"), 0);
+        names.insert(name("com"), 8);
+        let mx = RData::MX {
+            preference: 10,
+            exchange: name("mail.example.com"),
+        };
+        mx.serialize_compressed(&mut buffer, &mut names);
+        assert_eq!(buffer[13..], [0, 10, 4, 109, 97, 105, 108, 0xC0, 0]);
+
+        let srv = RData::SRV {
+            priority: 1,
+            weight: 5,
+            port: 5060,
+            target: name("example.com"),
+        };
+        let mut buffer = Vec::new();
+        srv.serialize_compressed(&mut buffer, &mut names);
+        assert_eq!(buffer, srv.serialize());
+    }
+
+    #[test]
+    fn test_rdata_bad_length() {
+        let result = RData::deserialize(&[127, 0, 0, 1, 0], 0, RecordType::A);
+        assert_eq!(
+            result.unwrap_err(),
+            ParseError::BadRdataLength {
+                rtype: RecordType::A,
+                len: 5
+            }
+        );
+    }
+
+    #[test]
+    fn test_rdata_compressed_name() {
+        // "example.com" at 0, followed by NS RDATA "ns1" plus a pointer back to it.
+        let buffer = [
+            7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 3, 110, 115, 49, 0xC0, 0,
+        ];
+        let rdata = RData::deserialize(&buffer, 13, RecordType::NS).unwrap();
+        assert_eq!(rdata, RData::NS(name("ns1.example.com")));
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("unrecognised mnemonic {0:?}")]
+pub struct MnemonicError(pub String);
+
+/// Declares a C-like enum over a protocol number, with an `Unknown` variant for values that have
+/// no mnemonic. Unknown values display as `<prefix><value>`, which `FromStr` also accepts, in the
+/// style of RFC 3597's `TYPE65534`.
+macro_rules! numbered_enum {
+    (
+        $(#[$meta:meta])*
+        $name:ident($repr:ty), unknown = $prefix:literal {
+            $($variant:ident = $value:literal => $mnemonic:literal,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[allow(clippy::upper_case_acronyms)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($variant,)*
+            Unknown($repr),
+        }
+
+        impl From<$repr> for $name {
+            fn from(value: $repr) -> Self {
+                return match value {
+                    $($value => $name::$variant,)*
+                    _ => $name::Unknown(value),
+                };
+            }
+        }
+
+        impl From<$name> for $repr {
+            fn from(value: $name) -> Self {
+                return match value {
+                    $($name::$variant => $value,)*
+                    $name::Unknown(value) => value,
+                };
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                return match self {
+                    $($name::$variant => write!(f, $mnemonic),)*
+                    $name::Unknown(value) => write!(f, concat!($prefix, "{}"), value),
+                };
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = MnemonicError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                let upper = s.to_ascii_uppercase();
+                $(if upper == $mnemonic {
+                    return Ok($name::$variant);
+                })*
+                return upper
+                    .strip_prefix($prefix)
+                    .and_then(|value| value.parse::<$repr>().ok())
+                    .map($name::from)
+                    .ok_or_else(|| MnemonicError(s.to_owned()));
+            }
+        }
+    };
+}
+
+numbered_enum! {
+    /// The TYPE of a resource record, or the QTYPE of a question.
+    RecordType(u16), unknown = "TYPE" {
+        A = 1 => "A",
+        NS = 2 => "NS",
+        CNAME = 5 => "CNAME",
+        SOA = 6 => "SOA",
+        PTR = 12 => "PTR",
+        HINFO = 13 => "HINFO",
+        MX = 15 => "MX",
+        TXT = 16 => "TXT",
+        AAAA = 28 => "AAAA",
+        SRV = 33 => "SRV",
+        NAPTR = 35 => "NAPTR",
+        OPT = 41 => "OPT",
+        DS = 43 => "DS",
+        RRSIG = 46 => "RRSIG",
+        NSEC = 47 => "NSEC",
+        DNSKEY = 48 => "DNSKEY",
+        NSEC3 = 50 => "NSEC3",
+        SVCB = 64 => "SVCB",
+        HTTPS = 65 => "HTTPS",
+        AXFR = 252 => "AXFR",
+        ANY = 255 => "ANY",
+        CAA = 257 => "CAA",
+    }
+}
+
+numbered_enum! {
+    /// The CLASS of a resource record, or the QCLASS of a question.
+    DnsClass(u16), unknown = "CLASS" {
+        IN = 1 => "IN",
+        CH = 3 => "CH",
+        HS = 4 => "HS",
+        NONE = 254 => "NONE",
+        ANY = 255 => "ANY",
+    }
+}
+
+numbered_enum! {
+    /// The kind of query carried by a message, from the 4-bit OPCODE header field.
+    Opcode(u16), unknown = "OPCODE" {
+        Query = 0 => "QUERY",
+        IQuery = 1 => "IQUERY",
+        Status = 2 => "STATUS",
+        Notify = 4 => "NOTIFY",
+        Update = 5 => "UPDATE",
+        Dso = 6 => "DSO",
+    }
+}
+
+numbered_enum! {
+    /// The outcome of a query. The header only carries the low 4 bits; larger codes need EDNS.
+    ResponseCode(u16), unknown = "RCODE" {
+        NoError = 0 => "NOERROR",
+        FormErr = 1 => "FORMERR",
+        ServFail = 2 => "SERVFAIL",
+        NXDomain = 3 => "NXDOMAIN",
+        NotImp = 4 => "NOTIMP",
+        Refused = 5 => "REFUSED",
+        YXDomain = 6 => "YXDOMAIN",
+        YXRRSet = 7 => "YXRRSET",
+        NXRRSet = 8 => "NXRRSET",
+        NotAuth = 9 => "NOTAUTH",
+        NotZone = 10 => "NOTZONE",
+        DsoTypeNI = 11 => "DSOTYPENI",
+        BadVers = 16 => "BADVERS",
+        BadKey = 17 => "BADKEY",
+        BadTime = 18 => "BADTIME",
+        BadMode = 19 => "BADMODE",
+        BadName = 20 => "BADNAME",
+        BadAlg = 21 => "BADALG",
+        BadTrunc = 22 => "BADTRUNC",
+        BadCookie = 23 => "BADCOOKIE",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_type_conversions() {
+        assert_eq!(RecordType::from(28), RecordType::AAAA);
+        assert_eq!(u16::from(RecordType::AAAA), 28);
+        assert_eq!(RecordType::from(65534), RecordType::Unknown(65534));
+        assert_eq!(u16::from(RecordType::Unknown(65534)), 65534);
+    }
+
+    #[test]
+    fn test_record_type_display() {
+        assert_eq!(RecordType::AAAA.to_string(), "AAAA");
+        assert_eq!(RecordType::from(65534).to_string(), "TYPE65534");
+    }
+
+    #[test]
+    fn test_record_type_from_str() {
+        assert_eq!("aaaa".parse::<RecordType>(), Ok(RecordType::AAAA));
+        assert_eq!(
+            "TYPE65534".parse::<RecordType>(),
+            Ok(RecordType::Unknown(65534))
+        );
+        assert_eq!("TYPE28".parse::<RecordType>(), Ok(RecordType::AAAA));
+        assert_eq!(
+            "BOGUS".parse::<RecordType>(),
+            Err(MnemonicError("BOGUS".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_dns_class() {
+        assert_eq!(DnsClass::from(1), DnsClass::IN);
+        assert_eq!(DnsClass::CH.to_string(), "CH");
+        assert_eq!("CLASS42".parse::<DnsClass>(), Ok(DnsClass::Unknown(42)));
+    }
+
+    #[test]
+    fn test_opcode() {
+        assert_eq!(Opcode::from(5), Opcode::Update);
+        assert_eq!(Opcode::Query.to_string(), "QUERY");
+        assert_eq!(Opcode::from(3).to_string(), "OPCODE3");
+    }
+
+    #[test]
+    fn test_response_code() {
+        assert_eq!(ResponseCode::from(3), ResponseCode::NXDomain);
+        assert_eq!(ResponseCode::NXDomain.to_string(), "NXDOMAIN");
+        assert_eq!(
+            "servfail".parse::<ResponseCode>(),
+            Ok(ResponseCode::ServFail)
+        );
+        assert_eq!(ResponseCode::from(12).to_string(), "RCODE12");
+    }
+}
@@ -0,0 +1,690 @@
+use crate::cache::{Cache, CacheKey};
+use crate::dns::{
+    DNSHeader, DnsQuery, DnsResponse, Edns, Opcode, RecordType, DEFAULT_UDP_PAYLOAD_SIZE,
+    MIN_UDP_PAYLOAD_SIZE,
+};
+use crate::querylog::{QueryLog, QueryRecord};
+use crate::upstream::{UpstreamError, UpstreamPool, UpstreamSpec};
+use futures::future::{join_all, BoxFuture, Shared};
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a client waits on the upstreams before getting a stale answer instead, where there
+/// is one (RFC 8767 section 5).
+const CLIENT_RESPONSE_TIMEOUT: Duration = Duration::from_millis(1800);
+
+/// An upstream query that any number of requests can wait on.
+type SharedLookup =
+    Shared<BoxFuture<'static, Result<(DnsResponse, UpstreamSpec), Arc<UpstreamError>>>>;
+
+/// How a reply will travel back to the client, which decides whether it has to be truncated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    /// TCP and anything layered over it, where messages can be up to 64KiB.
+    Stream,
+}
+
+/// Where the answers to a request came from, for the query log.
+#[derive(Debug, Default)]
+struct Origin {
+    /// The first upstream that answered one of its questions.
+    upstream: Option<UpstreamSpec>,
+    /// Whether every question was answered from the cache.
+    cache_hit: bool,
+}
+
+/// The path every client request takes, whichever listener it arrived on: parse it, send each
+/// question to the cache or the upstream resolvers and stitch the answers back into one reply.
+pub struct Forwarder {
+    upstreams: Arc<UpstreamPool>,
+    cache: Arc<Cache>,
+    /// The upstream queries under way, so that clients asking the same question at the same
+    /// time, with the same DNSSEC bits, share one.
+    in_flight: Arc<Mutex<HashMap<CacheKey, SharedLookup>>>,
+    query_log: QueryLog,
+}
+
+impl Forwarder {
+    pub fn new(upstreams: Arc<UpstreamPool>, cache: Arc<Cache>, query_log: QueryLog) -> Forwarder {
+        return Forwarder {
+            upstreams,
+            cache,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            query_log,
+        };
+    }
+
+    /// Handles one raw request and returns the serialized reply, or `None` if the request is
+    /// too mangled to reply to at all.
+    pub async fn handle(
+        &self,
+        request: &[u8],
+        client: SocketAddr,
+        transport: Transport,
+    ) -> Option<Vec<u8>> {
+        let time = SystemTime::now();
+        let started = Instant::now();
+        // Anything with QR set is a response, not a question for us; replying to it could bounce
+        // packets back and forth between two servers.
+        if request.get(2).is_some_and(|flags| flags & 0x80 != 0) {
+            return None;
+        }
+        let dns_query = match DnsQuery::deserialize(request) {
+            Ok(dns_query) if dns_query.header.opcode != Opcode::Query => {
+                let response = DnsResponse::not_implemented(&dns_query);
+                self.log(time, started, client, &response, Origin::default());
+                return Some(response.serialize());
+            }
+            Ok(dns_query) if !dns_query.questions.is_empty() => dns_query,
+            result => {
+                if let Err(e) = result {
+                    eprintln!("Malformed request from {}: {}", client, e);
+                }
+                let response = DnsResponse::format_error(request)?;
+                self.log(time, started, client, &response, Origin::default());
+                return Some(response.serialize());
+            }
+        };
+
+        let max_response_size = match (&dns_query.edns, transport) {
+            (_, Transport::Stream) => u16::MAX as usize,
+            (Some(edns), Transport::Udp) => edns.max_payload(),
+            (None, Transport::Udp) => MIN_UDP_PAYLOAD_SIZE as usize,
+        };
+
+        let (mut response, origin) = self.forward(dns_query).await;
+        if transport == Transport::Stream {
+            // Upstream answers are complete by now, and a stream has room for all of it.
+            response.header.tc = 0;
+        }
+        self.log(time, started, client, &response, origin);
+        return Some(response.serialize_truncated(max_response_size));
+    }
+
+    /// Logs a reply with one record per question, each counting all of the reply's answers. A
+    /// reply to a request whose questions couldn't be read is logged with an empty name.
+    fn log(
+        &self,
+        time: SystemTime,
+        started: Instant,
+        client: SocketAddr,
+        response: &DnsResponse,
+        origin: Origin,
+    ) {
+        let mut questions: Vec<(String, RecordType)> = response
+            .questions
+            .iter()
+            .map(|question| (question.name.to_string(), question.qtype))
+            .collect();
+        if questions.is_empty() {
+            questions.push((String::new(), RecordType::from(0)));
+        }
+        let upstream = origin.upstream.map(|spec| spec.to_string());
+        for (qname, qtype) in questions {
+            self.query_log.record(QueryRecord {
+                time,
+                client,
+                qname,
+                qtype,
+                rcode: response.response_code(),
+                answers: response.answers.len(),
+                upstream: upstream.clone(),
+                cache_hit: origin.cache_hit,
+                latency: started.elapsed(),
+            });
+        }
+    }
+
+    async fn forward(&self, mut dns_query: DnsQuery) -> (DnsResponse, Origin) {
+        let singular_queries = dns_query.split_questions();
+
+        let lookups = singular_queries.into_iter().map(|mut query| {
+            let edns = query
+                .edns
+                .get_or_insert_with(|| Edns::new(DEFAULT_UDP_PAYLOAD_SIZE));
+            // Always offer the upstream a large buffer; we truncate for the client ourselves if
+            // its own limit is smaller.
+            edns.udp_payload_size = DEFAULT_UDP_PAYLOAD_SIZE;
+            // Options such as cookies and client subnets are particular to one client, and the
+            // query may be shared with others, so none of them go upstream.
+            edns.options.clear();
+            return self.resolve(query);
+        });
+
+        let responses = join_all(lookups)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>();
+        return match responses {
+            Ok(responses) => {
+                let origin = Origin {
+                    upstream: responses.iter().find_map(|(_, upstream)| upstream.clone()),
+                    cache_hit: responses.iter().all(|(_, upstream)| upstream.is_none()),
+                };
+                let responses: Vec<DnsResponse> = responses
+                    .into_iter()
+                    .map(|(response, _)| response)
+                    .collect();
+                let header = DNSHeader {
+                    id: dns_query.header.id,
+                    ..responses[0].header.clone()
+                };
+                let extended_rcode = responses[0]
+                    .edns
+                    .as_ref()
+                    .map_or(0, |edns| edns.extended_rcode);
+                let mut answers = vec![];
+                let mut authority = vec![];
+                let mut additional = vec![];
+                for response in responses {
+                    answers.extend(response.answers);
+                    authority.extend(response.authority);
+                    additional.extend(response.additional);
+                }
+                // Only answer with an OPT record if the client sent one.
+                let edns = dns_query.edns.as_ref().map(|client_edns| Edns {
+                    extended_rcode,
+                    dnssec_ok: client_edns.dnssec_ok,
+                    ..Edns::new(DEFAULT_UDP_PAYLOAD_SIZE)
+                });
+                let response = DnsResponse {
+                    header,
+                    questions: dns_query.questions,
+                    answers,
+                    authority,
+                    additional,
+                    edns,
+                };
+                (response, origin)
+            }
+            Err(e) => {
+                eprintln!("Failed to query resolver: {}", e);
+                (DnsResponse::server_failure(&dns_query), Origin::default())
+            }
+        };
+    }
+
+    /// Answers a single question from the cache, or else from the upstreams, along with the
+    /// upstream that answered it.
+    async fn resolve(
+        &self,
+        query: DnsQuery,
+    ) -> Result<(DnsResponse, Option<UpstreamSpec>), Arc<UpstreamError>> {
+        let key = CacheKey::new(&query);
+        if let Some(response) = self.cache.get(&key) {
+            if self.cache.start_prefetch(&key) {
+                tokio::spawn(prefetch(self.upstreams.clone(), self.cache.clone(), query));
+            }
+            return Ok((response, None));
+        }
+
+        let lookup = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| self.lookup(query.clone()))
+            .clone();
+        if self.cache.is_stale(&key)
+            && tokio::time::timeout(CLIENT_RESPONSE_TIMEOUT, lookup.clone())
+                .await
+                .is_err()
+        {
+            if let Some(stale) = self.cache.get_stale(&key) {
+                tokio::spawn(finish_lookup(
+                    lookup,
+                    self.upstreams.clone(),
+                    self.cache.clone(),
+                    query,
+                ));
+                return Ok((stale, None));
+            }
+        }
+        return match lookup.await {
+            Ok((response, upstream)) => Ok((response, Some(upstream))),
+            Err(e) => {
+                // RFC 8767: an expired answer beats no answer while the upstreams are down.
+                let stale = self.cache.get_stale(&key).ok_or(e)?;
+                if self.cache.start_refresh(&key) {
+                    tokio::spawn(refresh_stale(
+                        self.upstreams.clone(),
+                        self.cache.clone(),
+                        query,
+                    ));
+                }
+                Ok((stale, None))
+            }
+        };
+    }
+
+    /// Queries the upstreams and caches the answer. Everyone asking the same question before
+    /// the answer arrives waits on this same query.
+    fn lookup(&self, query: DnsQuery) -> SharedLookup {
+        let upstreams = self.upstreams.clone();
+        let cache = self.cache.clone();
+        let in_flight = self.in_flight.clone();
+        return async move {
+            let key = CacheKey::new(&query);
+            let result = upstreams.query(query).await;
+            if let Ok((response, _)) = &result {
+                cache.insert(key.clone(), response);
+            }
+            in_flight.lock().unwrap().remove(&key);
+            result.map_err(Arc::new)
+        }
+        .boxed()
+        .shared();
+    }
+}
+
+/// Fetches a popular question again before its cache entry expires.
+async fn prefetch(upstreams: Arc<UpstreamPool>, cache: Arc<Cache>, query: DnsQuery) {
+    let key = CacheKey::new(&query);
+    match upstreams.query(query).await {
+        Ok((response, _)) => cache.insert(key, &response),
+        Err(e) => eprintln!("Failed to prefetch {}: {}", key.question.name, e),
+    }
+}
+
+/// Lets a lookup run on after its client was answered stale, and keeps retrying the question if
+/// it fails.
+async fn finish_lookup(
+    lookup: SharedLookup,
+    upstreams: Arc<UpstreamPool>,
+    cache: Arc<Cache>,
+    query: DnsQuery,
+) {
+    if lookup.await.is_err() && cache.start_refresh(&CacheKey::new(&query)) {
+        refresh_stale(upstreams, cache, query).await;
+    }
+}
+
+/// Keeps retrying a question that was answered stale until the upstreams answer it again, or
+/// until its cache entry is too old to serve anyway.
+async fn refresh_stale(upstreams: Arc<UpstreamPool>, cache: Arc<Cache>, query: DnsQuery) {
+    let key = CacheKey::new(&query);
+    loop {
+        tokio::time::sleep(cache.stale_refresh_interval()).await;
+        match upstreams.query(query.clone()).await {
+            Ok((response, _)) => return cache.insert(key, &response),
+            Err(_) if !cache.is_stale(&key) => return,
+            Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsClass, RData, ResourceRecord, ResponseCode};
+    use crate::querylog::Retention;
+    use crate::upstream::{DohMethod, RetryPolicy, Strategy, UpstreamSpec};
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::UdpSocket;
+
+    const QUERY: [u8; 29] = [
+        0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3,
+        b'c', b'o', b'm', 0, 0, 1, 0, 1,
+    ];
+
+    #[tokio::test]
+    async fn test_server_failure_when_upstream_is_silent() {
+        // Bound but never read, so every query goes unanswered.
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let retry = RetryPolicy {
+            timeout: Duration::from_millis(20),
+            attempts: 2,
+            backoff: Duration::from_millis(5),
+        };
+        let upstreams = UpstreamPool::new(
+            &[UpstreamSpec::Udp(silent.local_addr().unwrap())],
+            Strategy::Order,
+            retry,
+            crate::tls::client_config(None).unwrap(),
+            DohMethod::Post,
+        )
+        .await
+        .unwrap();
+        let forwarder = Forwarder::new(
+            Arc::new(upstreams),
+            Arc::new(Cache::new(100, Duration::ZERO)),
+            query_log(Arc::new(Mutex::new(sqlite::open(":memory:").unwrap()))),
+        );
+
+        let reply = forwarder
+            .handle(&QUERY, "127.0.0.1:5353".parse().unwrap(), Transport::Udp)
+            .await
+            .unwrap();
+        let response = DnsResponse::deserialize(&reply).unwrap();
+        assert_eq!(response.header.id, 0x1234);
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        assert_eq!(
+            response.questions,
+            DnsQuery::deserialize(&QUERY).unwrap().questions
+        );
+    }
+
+    /// Starts a resolver on 127.0.0.1 that answers its first `limit` queries with one A record
+    /// valid for `ttl` seconds and ignores the rest, and returns its address and a count of the
+    /// queries it has seen.
+    async fn fake_resolver(ttl: u32, limit: usize) -> (SocketAddr, Arc<AtomicUsize>) {
+        let resolver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = resolver.local_addr().unwrap();
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, from) = resolver.recv_from(&mut buf).await.unwrap();
+                if counter.fetch_add(1, Ordering::SeqCst) >= limit {
+                    continue;
+                }
+                let query = DnsQuery::deserialize(&buf[..len]).unwrap();
+                let mut response = DnsResponse::server_failure(&query);
+                response.header.rcode = ResponseCode::NoError;
+                response.answers.push(ResourceRecord::new(
+                    query.questions[0].name.clone(),
+                    DnsClass::IN,
+                    ttl,
+                    RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                ));
+                resolver.send_to(&response.serialize(), from).await.unwrap();
+            }
+        });
+        return (addr, received);
+    }
+
+    fn query_log(connection: Arc<Mutex<sqlite::Connection>>) -> QueryLog {
+        return QueryLog::start(connection, Retention::default()).unwrap();
+    }
+
+    async fn forwarder_to(addr: SocketAddr, cache: Cache) -> Forwarder {
+        let connection = Arc::new(Mutex::new(sqlite::open(":memory:").unwrap()));
+        return forwarder_logging_to(addr, cache, connection).await;
+    }
+
+    async fn forwarder_logging_to(
+        addr: SocketAddr,
+        cache: Cache,
+        connection: Arc<Mutex<sqlite::Connection>>,
+    ) -> Forwarder {
+        return forwarder_waiting(addr, cache, connection, Duration::from_millis(100)).await;
+    }
+
+    /// A forwarder that waits `timeout` for a single attempt at each upstream query.
+    async fn forwarder_waiting(
+        addr: SocketAddr,
+        cache: Cache,
+        connection: Arc<Mutex<sqlite::Connection>>,
+        timeout: Duration,
+    ) -> Forwarder {
+        let retry = RetryPolicy {
+            timeout,
+            attempts: 1,
+            backoff: Duration::ZERO,
+        };
+        let upstreams = UpstreamPool::new(
+            &[UpstreamSpec::Udp(addr)],
+            Strategy::Order,
+            retry,
+            crate::tls::client_config(None).unwrap(),
+            DohMethod::Post,
+        )
+        .await
+        .unwrap();
+        return Forwarder::new(Arc::new(upstreams), Arc::new(cache), query_log(connection));
+    }
+
+    async fn ask(forwarder: &Forwarder) -> DnsResponse {
+        let reply = forwarder
+            .handle(&QUERY, "127.0.0.1:5353".parse().unwrap(), Transport::Udp)
+            .await
+            .unwrap();
+        return DnsResponse::deserialize(&reply).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_second_query_served_from_cache() {
+        let (addr, received) = fake_resolver(300, usize::MAX).await;
+        let forwarder = forwarder_to(addr, Cache::new(100, Duration::ZERO)).await;
+        for _ in 0..2 {
+            let response = ask(&forwarder).await;
+            assert_eq!(response.header.id, 0x1234);
+            assert_eq!(response.answers.len(), 1);
+        }
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_drops_responses_and_rejects_other_opcodes() {
+        let (addr, received) = fake_resolver(300, usize::MAX).await;
+        let forwarder = forwarder_to(addr, Cache::new(100, Duration::ZERO)).await;
+        let client = "127.0.0.1:5353".parse().unwrap();
+
+        let mut response = QUERY;
+        response[2] |= 0x80;
+        assert!(forwarder
+            .handle(&response, client, Transport::Udp)
+            .await
+            .is_none());
+
+        // Opcode 4 is NOTIFY.
+        let mut notify = QUERY;
+        notify[2] = 4 << 3;
+        let reply = forwarder
+            .handle(&notify, client, Transport::Udp)
+            .await
+            .unwrap();
+        let reply = DnsResponse::deserialize(&reply).unwrap();
+        assert_eq!(reply.header.id, 0x1234);
+        assert_eq!(reply.header.opcode, Opcode::Notify);
+        assert_eq!(reply.response_code(), ResponseCode::NotImp);
+        assert_eq!(received.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_serves_stale_when_upstream_is_down() {
+        let (addr, received) = fake_resolver(1, 1).await;
+        let forwarder = forwarder_to(addr, Cache::new(100, Duration::from_secs(60))).await;
+        assert_eq!(ask(&forwarder).await.answers[0].ttl, 1);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        let response = ask(&forwarder).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answers[0].ttl, 30);
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_answers_stale_rather_than_wait_on_a_slow_upstream() {
+        let (addr, received) = fake_resolver(1, 1).await;
+        let connection = Arc::new(Mutex::new(sqlite::open(":memory:").unwrap()));
+        let cache = Cache::new(100, Duration::from_secs(60));
+        let forwarder = forwarder_waiting(addr, cache, connection, Duration::from_secs(10)).await;
+        assert_eq!(ask(&forwarder).await.answers[0].ttl, 1);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        let started = Instant::now();
+        let response = ask(&forwarder).await;
+        assert_eq!(response.answers[0].ttl, 30);
+        assert!(started.elapsed() < Duration::from_secs(3));
+        // The upstream query is still waiting on its reply.
+        assert_eq!(forwarder.in_flight.lock().unwrap().len(), 1);
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_identical_questions_share_one_upstream_query() {
+        let (addr, received) = fake_resolver(300, usize::MAX).await;
+        // With the cache off, only coalescing can save the upstream from repeat questions.
+        let forwarder = forwarder_to(addr, Cache::new(0, Duration::ZERO)).await;
+        let responses = join_all((0..5).map(|_| ask(&forwarder))).await;
+        for response in responses {
+            assert_eq!(response.header.id, 0x1234);
+            assert_eq!(response.answers.len(), 1);
+        }
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+        assert!(forwarder.in_flight.lock().unwrap().is_empty());
+
+        ask(&forwarder).await;
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+    }
+
+    /// `QUERY` with an OPT record carrying a client cookie, and the DO bit if `dnssec_ok`.
+    fn query_with_cookie(dnssec_ok: bool, cookie: u8) -> Vec<u8> {
+        let mut query = QUERY.to_vec();
+        query[11] = 1;
+        let flags = if dnssec_ok { 0x80 } else { 0 };
+        query.extend_from_slice(&[0, 0, 41, 4, 0xD0, 0, 0, flags, 0, 0, 12, 0, 10, 0, 8]);
+        query.extend_from_slice(&[cookie; 8]);
+        return query;
+    }
+
+    #[tokio::test]
+    async fn test_sharing_depends_on_dnssec_bits_but_not_options() {
+        let (addr, received) = fake_resolver(300, usize::MAX).await;
+        let forwarder = forwarder_to(addr, Cache::new(0, Duration::ZERO)).await;
+        let client = "127.0.0.1:5353".parse().unwrap();
+        let queries = [
+            query_with_cookie(false, 1),
+            query_with_cookie(false, 2),
+            query_with_cookie(true, 3),
+        ];
+        let replies = join_all(
+            queries
+                .iter()
+                .map(|query| forwarder.handle(query, client, Transport::Udp)),
+        )
+        .await;
+        for reply in replies {
+            assert_eq!(
+                DnsResponse::deserialize(&reply.unwrap())
+                    .unwrap()
+                    .answers
+                    .len(),
+                1
+            );
+        }
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_client_options_are_not_forwarded() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let forwarder = forwarder_to(
+            upstream.local_addr().unwrap(),
+            Cache::new(0, Duration::ZERO),
+        )
+        .await;
+        let query = query_with_cookie(true, 1);
+        let client = "127.0.0.1:5353".parse().unwrap();
+        let (_, received) = tokio::join!(forwarder.handle(&query, client, Transport::Udp), async {
+            let mut buf = [0; 512];
+            let (len, _) = upstream.recv_from(&mut buf).await.unwrap();
+            DnsQuery::deserialize(&buf[..len]).unwrap()
+        });
+        let edns = received.edns.unwrap();
+        assert!(edns.dnssec_ok);
+        assert!(edns.options.is_empty());
+    }
+
+    /// The upstream and cache hit flag of every query logged so far, checking that each one
+    /// was for `QUERY`.
+    fn logged(connection: &Mutex<sqlite::Connection>) -> Vec<(Option<String>, i64)> {
+        let connection = connection.lock().unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT client, qname, qtype, rcode, answers, upstream, cache_hit
+                FROM queries ORDER BY rowid",
+            )
+            .unwrap();
+        let mut rows = vec![];
+        while statement.next().unwrap() == sqlite::State::Row {
+            assert_eq!(statement.read::<String, _>(0).unwrap(), "127.0.0.1:5353");
+            assert_eq!(statement.read::<String, _>(1).unwrap(), "example.com.");
+            assert_eq!(statement.read::<String, _>(2).unwrap(), "A");
+            assert_eq!(statement.read::<String, _>(3).unwrap(), "NOERROR");
+            assert_eq!(statement.read::<i64, _>(4).unwrap(), 1);
+            rows.push((
+                statement.read::<Option<String>, _>(5).unwrap(),
+                statement.read::<i64, _>(6).unwrap(),
+            ));
+        }
+        return rows;
+    }
+
+    #[tokio::test]
+    async fn test_queries_are_logged() {
+        let (addr, _) = fake_resolver(300, usize::MAX).await;
+        let connection = Arc::new(Mutex::new(sqlite::open(":memory:").unwrap()));
+        let forwarder =
+            forwarder_logging_to(addr, Cache::new(100, Duration::ZERO), connection.clone()).await;
+        ask(&forwarder).await;
+        ask(&forwarder).await;
+
+        // The log is written in the background, so give it a moment.
+        for _ in 0..100 {
+            let rows = logged(&connection);
+            if rows.len() == 2 {
+                assert_eq!(rows[0], (Some(addr.to_string()), 0));
+                assert_eq!(rows[1], (None, 1));
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("queries were never logged");
+    }
+
+    #[tokio::test]
+    async fn test_every_question_and_error_is_logged() {
+        let (addr, _) = fake_resolver(300, usize::MAX).await;
+        let connection = Arc::new(Mutex::new(sqlite::open(":memory:").unwrap()));
+        let forwarder =
+            forwarder_logging_to(addr, Cache::new(100, Duration::ZERO), connection.clone()).await;
+        let client = "127.0.0.1:5353".parse().unwrap();
+
+        // Two questions, for example.com and example.org.
+        let mut two_questions = QUERY.to_vec();
+        two_questions[5] = 2;
+        two_questions.extend_from_slice(&QUERY[12..]);
+        two_questions[38..41].copy_from_slice(b"org");
+        forwarder
+            .handle(&two_questions, client, Transport::Udp)
+            .await;
+        // Claims a question but ends before it.
+        forwarder.handle(&QUERY[..12], client, Transport::Udp).await;
+
+        let select = "SELECT qname, qtype, rcode FROM queries ORDER BY rowid";
+        for _ in 0..100 {
+            let rows: Vec<(String, String, String)> = {
+                let connection = connection.lock().unwrap();
+                let mut statement = connection.prepare(select).unwrap();
+                let mut rows = vec![];
+                while statement.next().unwrap() == sqlite::State::Row {
+                    rows.push((
+                        statement.read(0).unwrap(),
+                        statement.read(1).unwrap(),
+                        statement.read(2).unwrap(),
+                    ));
+                }
+                rows
+            };
+            if rows.len() == 3 {
+                let row = |qname: &str, qtype: &str, rcode: &str| {
+                    (qname.to_owned(), qtype.to_owned(), rcode.to_owned())
+                };
+                assert_eq!(rows[0], row("example.com.", "A", "NOERROR"));
+                assert_eq!(rows[1], row("example.org.", "A", "NOERROR"));
+                assert_eq!(rows[2], row("", "TYPE0", "FORMERR"));
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("queries were never logged");
+    }
+}
@@ -0,0 +1,55 @@
+//! The two-byte length framing DNS uses over TCP (RFC 1035 section 4.2.2), and over TLS and
+//! anything else stream-based.
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Reads one length-prefixed message, or `None` if the client closed the connection cleanly
+/// between messages.
+pub async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let mut length = [0; 2];
+    match reader.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut message = vec![0; u16::from_be_bytes(length) as usize];
+    reader.read_exact(&mut message).await?;
+    return Ok(Some(message));
+}
+
+/// Prefixes a message with its length, ready to be written to a stream.
+pub fn frame(message: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    framed.extend_from_slice(message);
+    return framed;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_message() {
+        let mut framed: &[u8] = &[0, 3, 1, 2, 3, 0, 0];
+        assert_eq!(
+            read_message(&mut framed).await.unwrap(),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(read_message(&mut framed).await.unwrap(), Some(vec![]));
+        assert_eq!(read_message(&mut framed).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_message_truncated() {
+        let mut framed: &[u8] = &[0, 3, 1, 2];
+        assert!(read_message(&mut framed).await.is_err());
+    }
+
+    #[test]
+    fn test_frame() {
+        assert_eq!(frame(&[1, 2, 3]), [0, 3, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,811 @@
+use crate::cache::Cache;
+use crate::dns::DnsResponse;
+use crate::forwarder::{Forwarder, Transport};
+use crate::querylog::{search, QueryFilter, SearchError};
+use crate::server::ACCEPT_ERROR_PAUSE;
+use crate::upstream::{UpstreamPool, DNS_MESSAGE};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio_rustls::TlsAcceptor;
+
+/// The longest request head (request line and headers) we will read.
+const MAX_HEAD_LENGTH: usize = 8192;
+/// How long a connection may sit between requests before we close it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many logged queries `/api/queries` returns unless asked for a different number.
+const DEFAULT_PAGE_SIZE: u32 = 100;
+/// The most logged queries `/api/queries` returns at once.
+const MAX_PAGE_SIZE: u32 = 1000;
+
+/// Everything the HTTP endpoints need to answer requests.
+pub struct HttpContext {
+    pub connection: Arc<Mutex<sqlite::Connection>>,
+    pub upstreams: Arc<UpstreamPool>,
+    pub cache: Arc<Cache>,
+    pub forwarder: Arc<Forwarder>,
+}
+
+/// Which endpoints a listener serves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Endpoints {
+    /// The stats page, the query log API and DNS-over-HTTPS.
+    All,
+    /// DNS-over-HTTPS alone, for the listener that faces clients.
+    DnsOnly,
+}
+
+/// Serves the stats page, the query log API and DNS-over-HTTPS over plain HTTP/1.1, to at most
+/// `max_connections` clients at once.
+pub async fn serve_http(listener: TcpListener, context: Arc<HttpContext>, max_connections: usize) {
+    let connections = Arc::new(Semaphore::new(max_connections));
+
+    loop {
+        let (stream, client) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Error accepting HTTP connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_PAUSE).await;
+                continue;
+            }
+        };
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            eprintln!(
+                "Refusing HTTP connection from {}: too many connections",
+                client
+            );
+            continue;
+        };
+        let context = context.clone();
+        tokio::spawn(async move {
+            serve_http1(stream, client, context, Endpoints::All).await;
+            drop(permit);
+        });
+    }
+}
+
+/// Serves DNS-over-HTTPS alone over TLS, speaking HTTP/2 to clients that negotiate it and
+/// HTTP/1.1 to the rest, to at most `max_connections` clients at once. The stats page and query
+/// log stay on the `serve_http` listener.
+pub async fn serve_https(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    context: Arc<HttpContext>,
+    max_connections: usize,
+) {
+    let connections = Arc::new(Semaphore::new(max_connections));
+
+    loop {
+        let (stream, client) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Error accepting HTTPS connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_PAUSE).await;
+                continue;
+            }
+        };
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            eprintln!(
+                "Refusing HTTPS connection from {}: too many connections",
+                client
+            );
+            continue;
+        };
+        let acceptor = acceptor.clone();
+        let context = context.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(IDLE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) if stream.get_ref().1.alpn_protocol() == Some(b"h2") => {
+                    serve_h2(stream, client, context, Endpoints::DnsOnly).await;
+                }
+                Ok(Ok(stream)) => {
+                    serve_http1(stream, client, context, Endpoints::DnsOnly).await;
+                }
+                Ok(Err(e)) => eprintln!("TLS handshake with {} failed: {}", client, e),
+                Err(_) => eprintln!("TLS handshake with {} timed out", client),
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn serve_http1<S>(
+    stream: S,
+    client: SocketAddr,
+    context: Arc<HttpContext>,
+    endpoints: Endpoints,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+    loop {
+        let request = match tokio::time::timeout(IDLE_TIMEOUT, read_request(&mut stream)).await {
+            Ok(Ok(Some(request))) => request,
+            Ok(Ok(None)) | Err(_) => break,
+            Ok(Err(e)) => {
+                eprintln!("Bad HTTP request from {}: {}", client, e);
+                let response = Response::text(400, "Bad request");
+                let _ = stream.write_all(&response.serialize()).await;
+                break;
+            }
+        };
+        let keep_alive = request.http_version == "HTTP/1.1"
+            && !request
+                .header("Connection")
+                .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+        let response = route(&context, endpoints, request, client).await;
+        if stream.write_all(&response.serialize()).await.is_err() || !keep_alive {
+            break;
+        }
+    }
+    let _ = stream.shutdown().await;
+}
+
+async fn serve_h2<S>(stream: S, client: SocketAddr, context: Arc<HttpContext>, endpoints: Endpoints)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut connection = match h2::server::handshake(stream).await {
+        Ok(connection) => connection,
+        Err(e) => return eprintln!("HTTP/2 handshake with {} failed: {}", client, e),
+    };
+    while let Some(Ok((request, mut respond))) = connection.accept().await {
+        let context = context.clone();
+        tokio::spawn(async move {
+            let (parts, mut body) = request.into_parts();
+            let mut content = Vec::new();
+            while let Some(Ok(chunk)) = body.data().await {
+                let _ = body.flow_control().release_capacity(chunk.len());
+                content.extend_from_slice(&chunk);
+                if content.len() > u16::MAX as usize {
+                    break;
+                }
+            }
+            let request = Request {
+                method: parts.method.to_string(),
+                headers: parts
+                    .headers
+                    .iter()
+                    .map(|(name, value)| {
+                        format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes()))
+                    })
+                    .collect(),
+                path: parts
+                    .uri
+                    .path_and_query()
+                    .map_or("/".to_owned(), |path| path.to_string()),
+                http_version: "HTTP/2".to_owned(),
+                content,
+            };
+
+            let response = route(&context, endpoints, request, client).await;
+            let mut head = http::Response::builder().status(response.status_code);
+            for header in &response.headers {
+                if let Some((name, value)) = header.split_once(':') {
+                    head = head.header(name.trim(), value.trim());
+                }
+            }
+            let Ok(head) = head.body(()) else {
+                return;
+            };
+            if let Ok(mut body) = respond.send_response(head, false) {
+                let _ = body.send_data(Bytes::from(response.body), true);
+            }
+        });
+    }
+}
+
+async fn route(
+    context: &HttpContext,
+    endpoints: Endpoints,
+    request: Request,
+    client: SocketAddr,
+) -> Response {
+    let (path, query) = match request.path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (request.path.as_str(), None),
+    };
+    return match (endpoints, path) {
+        (_, "/dns-query") => dns_query(context, &request, query, client).await,
+        (Endpoints::All, "/") => stats(context).await,
+        (Endpoints::All, "/api/queries") => api_queries(context, &request, query).await,
+        _ => Response::text(404, "Not found"),
+    };
+}
+
+async fn stats(context: &HttpContext) -> Response {
+    let connection = context.connection.clone();
+    let request_count = tokio::task::spawn_blocking(move || {
+        let connection = connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT COUNT(*) FROM queries").unwrap();
+        statement.next().unwrap();
+        return statement.read::<i64, _>(0).unwrap();
+    })
+    .await
+    .unwrap();
+
+    let response_body = format!(
+        "There have been {} requests\n\n{}\n{}",
+        request_count, context.cache, context.upstreams
+    );
+    return Response::text(200, &response_body);
+}
+
+/// The DNS-over-HTTPS endpoint from RFC 8484.
+async fn dns_query(
+    context: &HttpContext,
+    request: &Request,
+    query: Option<&str>,
+    client: SocketAddr,
+) -> Response {
+    let message = match request.method.as_str() {
+        "GET" => {
+            let encoded = query
+                .into_iter()
+                .flat_map(|query| query.split('&'))
+                .find_map(|param| param.strip_prefix("dns="));
+            match encoded.map(|encoded| URL_SAFE_NO_PAD.decode(encoded.trim_end_matches('='))) {
+                Some(Ok(message)) => message,
+                _ => return Response::text(400, "Expected a base64url dns parameter"),
+            }
+        }
+        "POST" => {
+            if request.header("Content-Type") != Some(DNS_MESSAGE) {
+                return Response::text(415, "Expected application/dns-message");
+            }
+            request.content.clone()
+        }
+        _ => return Response::text(405, "Use GET or POST"),
+    };
+
+    let Some(reply) = context
+        .forwarder
+        .handle(&message, client, Transport::Stream)
+        .await
+    else {
+        return Response::text(400, "Malformed DNS message");
+    };
+    let mut headers = vec![format!("Content-Type: {}", DNS_MESSAGE)];
+    // Let HTTP caches keep the answer for as long as its shortest TTL, as RFC 8484 suggests.
+    if let Ok(response) = DnsResponse::deserialize(&reply) {
+        let min_ttl = response
+            .answers
+            .iter()
+            .chain(&response.authority)
+            .map(|record| record.ttl)
+            .min();
+        if let Some(min_ttl) = min_ttl {
+            headers.push(format!("Cache-Control: max-age={}", min_ttl));
+        }
+    }
+    return Response {
+        status_code: 200,
+        headers,
+        body: reply,
+    };
+}
+
+/// Searches the query log. Takes `client`, `qname`, `qtype`, `rcode`, `since` and `until`
+/// filters plus `limit` and `offset` for paging, and answers with JSON.
+async fn api_queries(context: &HttpContext, request: &Request, query: Option<&str>) -> Response {
+    if request.method != "GET" {
+        return Response::text(405, "Use GET");
+    }
+    let mut filter = QueryFilter {
+        limit: DEFAULT_PAGE_SIZE,
+        ..QueryFilter::default()
+    };
+    for param in query.into_iter().flat_map(|query| query.split('&')) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        let Some(value) = percent_decode(value) else {
+            return Response::text(400, "Malformed query string");
+        };
+        if value.is_empty() {
+            continue;
+        }
+        match name {
+            "client" => filter.client = Some(value),
+            "qname" => filter.qname = Some(value),
+            "qtype" => filter.qtype = Some(value),
+            "rcode" => filter.rcode = Some(value),
+            "since" => filter.since = Some(value),
+            "until" => filter.until = Some(value),
+            "limit" => match value.parse() {
+                Ok(limit) if (1..=MAX_PAGE_SIZE).contains(&limit) => filter.limit = limit,
+                _ => {
+                    let message = format!("limit must be between 1 and {}", MAX_PAGE_SIZE);
+                    return Response::text(400, &message);
+                }
+            },
+            "offset" => match value.parse() {
+                Ok(offset) => filter.offset = offset,
+                Err(_) => return Response::text(400, "offset must be a whole number"),
+            },
+            _ => {}
+        }
+    }
+
+    let (limit, offset) = (filter.limit, filter.offset);
+    let connection = context.connection.clone();
+    // The text filters scan the whole table, so keep them off the runtime's workers.
+    let result = tokio::task::spawn_blocking(move || {
+        return search(&connection.lock().unwrap(), &filter);
+    })
+    .await
+    .unwrap();
+    let page = match result {
+        Ok(page) => page,
+        Err(e @ (SearchError::InvalidTime(_) | SearchError::InvalidOffset(_))) => {
+            return Response::text(400, &e.to_string());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return Response::text(500, "Failed to read the query log");
+        }
+    };
+    let queries: Vec<String> = page
+        .queries
+        .iter()
+        .map(|query| {
+            format!(
+                "{{\"time\":{},\"client\":{},\"qname\":{},\"qtype\":{},\"rcode\":{},\
+                \"answers\":{},\"upstream\":{},\"cache_hit\":{},\"latency_ms\":{}}}",
+                json_string(&query.time),
+                json_string(&query.client),
+                json_string(&query.qname),
+                json_string(&query.qtype),
+                json_string(&query.rcode),
+                query.answers,
+                query
+                    .upstream
+                    .as_deref()
+                    .map_or("null".to_owned(), json_string),
+                query.cache_hit,
+                query.latency_ms
+            )
+        })
+        .collect();
+    let body = format!(
+        "{{\"total\":{},\"limit\":{},\"offset\":{},\"queries\":[{}]}}",
+        page.total,
+        limit,
+        offset,
+        queries.join(",")
+    );
+    return Response::json(200, body);
+}
+
+/// Decodes a percent-encoded query string value, or returns `None` if it is malformed.
+fn percent_decode(value: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        decoded.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        });
+    }
+    return String::from_utf8(decoded).ok();
+}
+
+/// Quotes a string for JSON.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    return quoted;
+}
+
+/// Reads one HTTP/1.1 request, or `None` if the client closed the connection between requests.
+async fn read_request<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+) -> std::io::Result<Option<Request>> {
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            if head.is_empty() {
+                return Ok(None);
+            }
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if head.len() + line.len() > MAX_HEAD_LENGTH {
+            return Err(invalid("request head too long"));
+        }
+        if line.trim_end().is_empty() {
+            if head.is_empty() {
+                // Tolerate stray blank lines between requests.
+                continue;
+            }
+            break;
+        }
+        head.push_str(&line);
+    }
+
+    let mut request = parse_request(&head).ok_or_else(|| invalid("malformed request head"))?;
+    let content_length = match request.header("Content-Length") {
+        Some(length) => length
+            .parse::<usize>()
+            .map_err(|_| invalid("bad Content-Length"))?,
+        None => 0,
+    };
+    if content_length > u16::MAX as usize {
+        return Err(invalid("request body too large"));
+    }
+    request.content = vec![0; content_length];
+    reader.read_exact(&mut request.content).await?;
+    return Ok(Some(request));
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+}
+
+/// Parses a request line and headers. The body is read separately.
+fn parse_request(head: &str) -> Option<Request> {
+    let mut lines = head.lines();
+    let first_line = lines.next()?;
+    let mut parts = first_line.split_whitespace();
+    let method = parts.next()?.to_owned();
+    let path = parts.next()?.to_owned();
+    let http_version = parts.next()?.to_owned();
+
+    let headers = lines.map(|line| line.trim_end().to_owned()).collect();
+
+    return Some(Request {
+        method,
+        headers,
+        path,
+        http_version,
+        content: Vec::new(),
+    });
+}
+
+struct Request {
+    method: String,
+    headers: Vec<String>,
+    path: String,
+    http_version: String,
+    content: Vec<u8>,
+}
+
+impl Request {
+    /// The value of the first header with the given name, which is matched case-insensitively.
+    fn header(&self, name: &str) -> Option<&str> {
+        return self.headers.iter().find_map(|header| {
+            let (header_name, value) = header.split_once(':')?;
+            header_name
+                .trim()
+                .eq_ignore_ascii_case(name)
+                .then(|| value.trim())
+        });
+    }
+}
+
+struct Response {
+    status_code: u16,
+    headers: Vec<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn text(status_code: u16, body: &str) -> Response {
+        return Response {
+            status_code,
+            headers: vec!["Content-Type: text/plain".to_owned()],
+            body: body.as_bytes().to_vec(),
+        };
+    }
+
+    fn json(status_code: u16, body: String) -> Response {
+        return Response {
+            status_code,
+            headers: vec!["Content-Type: application/json".to_owned()],
+            body: body.into_bytes(),
+        };
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {}\r\n", self.status_code);
+        self.headers.iter().for_each(|header| {
+            response.push_str(&format!("{}\r\n", header));
+        });
+        response.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        response.push_str("\r\n");
+        let mut response = response.into_bytes();
+        response.extend_from_slice(&self.body);
+        return response;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsQuery, ResponseCode};
+    use crate::querylog::{QueryLog, Retention};
+    use crate::upstream::{DohEndpoint, DohMethod, HttpsUpstream, RetryPolicy, Strategy};
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+    /// A question-less query, which the forwarder answers with FORMERR without going upstream.
+    const EMPTY_QUERY: [u8; 12] = [0, 7, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    async fn context() -> Arc<HttpContext> {
+        let connection = Arc::new(Mutex::new(sqlite::open(":memory:").unwrap()));
+        let query_log = QueryLog::start(connection.clone(), Retention::default()).unwrap();
+        let retry = RetryPolicy {
+            timeout: Duration::from_millis(10),
+            attempts: 1,
+            backoff: Duration::ZERO,
+        };
+        let upstreams = Arc::new(
+            UpstreamPool::new(
+                &["127.0.0.1:9".parse().unwrap()],
+                Strategy::Order,
+                retry,
+                crate::tls::client_config(None).unwrap(),
+                DohMethod::Post,
+            )
+            .await
+            .unwrap(),
+        );
+        let cache = Arc::new(Cache::new(100, Duration::ZERO));
+        return Arc::new(HttpContext {
+            connection,
+            forwarder: Arc::new(Forwarder::new(upstreams.clone(), cache.clone(), query_log)),
+            upstreams,
+            cache,
+        });
+    }
+
+    /// Sends raw HTTP/1.1 requests over one connection and returns everything written back.
+    async fn exchange(requests: &[u8]) -> Vec<u8> {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(serve_http1(
+            server,
+            "127.0.0.1:5353".parse().unwrap(),
+            context().await,
+            Endpoints::All,
+        ));
+        client.write_all(requests).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        server.await.unwrap();
+        return response;
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request =
+            parse_request("GET /dns-query?dns=AAAB HTTP/1.1\r\nhost: proxy\r\nAccept: */*\r\n")
+                .unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/dns-query?dns=AAAB");
+        assert_eq!(request.http_version, "HTTP/1.1");
+        assert_eq!(request.header("Host"), Some("proxy"));
+        assert_eq!(request.header("Content-Length"), None);
+        assert!(parse_request("GET\r\n").is_none());
+    }
+
+    #[test]
+    fn test_serialize_response() {
+        let response = Response::text(404, "Not found");
+        assert_eq!(
+            response.serialize(),
+            b"HTTP/1.1 404\r\nContent-Type: text/plain\r\nContent-Length: 9\r\n\r\nNot found"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_request_with_body() {
+        let raw: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET";
+        let mut reader = BufReader::new(raw);
+        let request = read_request(&mut reader).await.unwrap().unwrap();
+        assert_eq!(request.content, b"abc");
+        assert!(read_request(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dns_query_post() {
+        let mut request = format!(
+            "POST /dns-query HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            DNS_MESSAGE,
+            EMPTY_QUERY.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(&EMPTY_QUERY);
+        let response = exchange(&request).await;
+        let head =
+            b"HTTP/1.1 200\r\nContent-Type: application/dns-message\r\nContent-Length: 12\r\n\r\n";
+        assert!(response.starts_with(head));
+        let reply = &response[head.len()..];
+        assert_eq!(reply[1], 7);
+        assert_eq!(reply[3] & 0b1111, 1);
+    }
+
+    #[tokio::test]
+    async fn test_dns_query_get_keeps_connection_open() {
+        let get = format!(
+            "GET /dns-query?dns={} HTTP/1.1\r\n\r\n",
+            URL_SAFE_NO_PAD.encode(EMPTY_QUERY)
+        );
+        let response = exchange(format!("{}{}", get, get).as_bytes()).await;
+        let responses = response
+            .windows(12)
+            .filter(|window| window == b"HTTP/1.1 200")
+            .count();
+        assert_eq!(responses, 2);
+    }
+
+    #[tokio::test]
+    async fn test_dns_query_rejects_bad_requests() {
+        let response = exchange(b"POST /dns-query HTTP/1.1\r\nContent-Length: 0\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 415"));
+        let response = exchange(b"GET /dns-query?dns=!! HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 400"));
+        let response = exchange(b"PUT /dns-query HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 405"));
+        let response = exchange(b"GET /elsewhere HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 404"));
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let response = exchange(b"GET / HTTP/1.1\r\n\r\n").await;
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("There have been 0 requests"));
+        assert!(response.contains("127.0.0.1:9 healthy"));
+    }
+
+    #[tokio::test]
+    async fn test_api_queries() {
+        let context = context().await;
+        context
+            .connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO queries VALUES
+                ('2024-05-01T10:00:00.000Z', '10.0.0.7:5353', 'example.com.', 'A', 'NOERROR',
+                    1, '1.1.1.1:53', 0, 12.5),
+                ('2024-05-01T10:01:00.000Z', '10.0.0.7:5353', 'example.com.', 'A', 'NOERROR',
+                    1, NULL, 1, 0.25),
+                ('2024-05-01T10:02:00.000Z', '10.0.0.8:5353', 'example.org.', 'A', 'NXDOMAIN',
+                    0, '1.1.1.1:53', 0, 20)",
+            )
+            .unwrap();
+        let request = parse_request(
+            "GET /api/queries?client=10.0.0.7&qname=EXAMPLE%2Ecom&since=2024-05-01+09%3A00&limit=1 \
+            HTTP/1.1\r\n",
+        )
+        .unwrap();
+        let client = "127.0.0.1:5353".parse().unwrap();
+        let response = route(&context, Endpoints::All, request, client).await;
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.headers, vec!["Content-Type: application/json"]);
+        assert_eq!(
+            String::from_utf8(response.body).unwrap(),
+            "{\"total\":2,\"limit\":1,\"offset\":0,\"queries\":[{\"time\":\"2024-05-01T10:01:00.000Z\",\
+            \"client\":\"10.0.0.7:5353\",\"qname\":\"example.com.\",\"qtype\":\"A\",\
+            \"rcode\":\"NOERROR\",\"answers\":1,\"upstream\":null,\"cache_hit\":true,\
+            \"latency_ms\":0.25}]}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_api_queries_rejects_bad_requests() {
+        let response = exchange(b"GET /api/queries?limit=0 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 400"));
+        let response = exchange(b"GET /api/queries?since=soon HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 400"));
+        let response = exchange(b"GET /api/queries?qname=%zz HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 400"));
+        let response =
+            exchange(b"GET /api/queries?offset=18446744073709551615 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 400"));
+        let response = exchange(b"POST /api/queries HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 405"));
+        let response = exchange(b"GET /api/queries HTTP/1.1\r\n\r\n").await;
+        assert!(response.ends_with(b"{\"total\":0,\"limit\":100,\"offset\":0,\"queries\":[]}"));
+    }
+
+    #[tokio::test]
+    async fn test_dns_only_endpoints() {
+        let context = context().await;
+        let client = "127.0.0.1:5353".parse().unwrap();
+        for path in ["/", "/api/queries"] {
+            let request = parse_request(&format!("GET {} HTTP/1.1\r\n", path)).unwrap();
+            let response = route(&context, Endpoints::DnsOnly, request, client).await;
+            assert_eq!(response.status_code, 404);
+        }
+        let request = parse_request(&format!(
+            "GET /dns-query?dns={} HTTP/1.1\r\n",
+            URL_SAFE_NO_PAD.encode(EMPTY_QUERY)
+        ))
+        .unwrap();
+        let response = route(&context, Endpoints::DnsOnly, request, client).await;
+        assert_eq!(response.status_code, 200);
+    }
+
+    #[test]
+    fn test_percent_decode_and_json_string() {
+        assert_eq!(percent_decode("a%2Eb+c").as_deref(), Some("a.b c"));
+        assert_eq!(percent_decode("%4"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(
+            json_string("say \"hi\"\\\n"),
+            "\"say \\\"hi\\\"\\\\\\u000a\""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve_https_over_h2() {
+        let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).unwrap();
+        let cert = CertificateDer::from(certified.cert.der().to_vec());
+        let key = PrivateKeyDer::try_from(certified.key_pair.serialize_der()).unwrap();
+        let mut server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_https(
+            listener,
+            TlsAcceptor::from(Arc::new(server_config)),
+            context().await,
+            16,
+        ));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let endpoint = DohEndpoint::parse(&format!("https://{}/dns-query", addr)).unwrap();
+        let upstream = HttpsUpstream::new(endpoint, DohMethod::Post, Arc::new(client_config));
+
+        // Our own upstream never answers, so the proxy replies SERVFAIL for the question.
+        let query = DnsQuery::deserialize(&[
+            0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 3, b'c', b'o', b'm', 0, 0, 1, 0, 1,
+        ])
+        .unwrap();
+        let response = upstream.query(query).await.unwrap();
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+    }
+
+    #[tokio::test]
+    async fn test_serve_http_refuses_connections_over_the_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_http(listener, context().await, 1));
+
+        let _first = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut second = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(second.read(&mut buf).await.unwrap(), 0);
+    }
+}
@@ -1,212 +1,191 @@
-use std::fmt::Display;
-use std::io::{Read, Write};
-use crate::dns::{DnsQuery, DnsResponse};
+#![allow(clippy::needless_return)]
+
+use crate::cache::Cache;
+use crate::forwarder::Forwarder;
+use crate::http::HttpContext;
+use crate::querylog::{QueryLog, Retention};
+use crate::server::StreamLimits;
+use crate::upstream::{DohMethod, RetryPolicy, Strategy, UpstreamPool, UpstreamSpec};
 use clap::Parser;
-use futures::future::join_all;
-use std::net::{IpAddr, SocketAddr, TcpListener};
-use std::str::FromStr;
-use std::sync::Arc;
-use tokio::net::UdpSocket;
-
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio_rustls::TlsAcceptor;
+
+mod cache;
 mod dns;
+mod forwarder;
+mod framing;
+mod http;
+mod querylog;
+mod server;
+mod tls;
+mod upstream;
 
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Args {
-    #[arg(short, long)]
-    resolver: String,
-}
-
-impl From<Args> for SocketAddr {
-    fn from(value: Args) -> Self {
-        let parts = value.resolver.split(":").collect::<Vec<&str>>();
-        if parts.len() == 2 {
-            return SocketAddr::new(
-                IpAddr::from_str(parts[0]).expect("Invalid IP address"),
-                u16::from_str(parts[1]).expect("Invalid port number"),
-            );
-        } else {
-            panic!("Invalid resolver address. Resolver address must be in the format IP:PORT");
-        }
-    }
+    /// Upstream resolver as IP:PORT, tls://IP:PORT#NAME or https://IP:PORT/PATH#NAME; repeat to
+    /// forward to several
+    #[arg(short, long, required = true)]
+    resolver: Vec<UpstreamSpec>,
+    /// How to choose between several upstream resolvers
+    #[arg(long, value_enum, default_value_t = Strategy::Order)]
+    strategy: Strategy,
+    /// Seconds a TCP or TLS connection may sit idle before it is closed
+    #[arg(long, default_value_t = 10)]
+    tcp_idle_timeout: u64,
+    /// Maximum number of concurrent connections on each TCP, TLS or HTTP listener
+    #[arg(long, default_value_t = 100)]
+    tcp_max_connections: usize,
+    /// Milliseconds to wait for each upstream attempt
+    #[arg(long, default_value_t = 2000)]
+    upstream_timeout: u64,
+    /// Attempts per upstream query before answering SERVFAIL
+    #[arg(long, default_value_t = 3)]
+    upstream_attempts: u32,
+    /// Milliseconds to wait before the first retry, doubling each time
+    #[arg(long, default_value_t = 100)]
+    upstream_backoff: u64,
+    /// HTTP method for DNS-over-HTTPS upstreams
+    #[arg(long, value_enum, default_value_t = DohMethod::Post)]
+    doh_method: DohMethod,
+    /// Maximum number of responses to cache; 0 turns the cache off
+    #[arg(long, default_value_t = 10000)]
+    cache_size: usize,
+    /// Seconds past expiry that cached answers may still be served while all upstreams are
+    /// failing
+    #[arg(long, default_value_t = 86400)]
+    max_stale: u64,
+    /// SQLite database to keep the query log in; without one the log is lost on restart
+    #[arg(long)]
+    query_log: Option<PathBuf>,
+    /// Seconds to keep query log entries for; 0 keeps them forever
+    #[arg(long, default_value_t = 7 * 24 * 60 * 60)]
+    query_log_max_age: u64,
+    /// Maximum number of query log entries to keep; 0 means no limit
+    #[arg(long, default_value_t = 1_000_000)]
+    query_log_max_rows: u64,
+    /// Address for the HTTP listener with the stats page, the query log API and
+    /// DNS-over-HTTPS; neither the stats nor the log are protected, so keep it private
+    #[arg(long, default_value = "127.0.0.1:80")]
+    http_addr: SocketAddr,
+    /// PEM bundle of CA certificates to trust for TLS and HTTPS upstreams instead of the
+    /// built-in roots
+    #[arg(long)]
+    upstream_ca_file: Option<PathBuf>,
+    /// Port to accept DNS-over-TLS connections on; needs --tls-cert and --tls-key
+    #[arg(long, requires_all = ["tls_cert", "tls_key"])]
+    dot_port: Option<u16>,
+    /// Port to accept DNS-over-HTTPS requests on over TLS; needs --tls-cert and --tls-key
+    #[arg(long, requires_all = ["tls_cert", "tls_key"])]
+    doh_port: Option<u16>,
+    /// PEM certificate chain presented to DNS-over-TLS and DNS-over-HTTPS clients
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    let resolver: SocketAddr = args.into();
-    let connection = sqlite::open(":memory:").unwrap();
-    let query = "
-        CREATE TABLE queries (query TEXT, time TEXT);
-    ";
-    connection.execute(query).unwrap();
+    let connection = match &args.query_log {
+        Some(path) => sqlite::open(path),
+        None => sqlite::open(":memory:"),
+    };
+    let connection = Arc::new(Mutex::new(
+        connection.expect("Failed to open the query log"),
+    ));
+    let retention = Retention {
+        max_age: Some(Duration::from_secs(args.query_log_max_age)).filter(|age| !age.is_zero()),
+        max_rows: Some(args.query_log_max_rows).filter(|&rows| rows > 0),
+    };
+    let query_log =
+        QueryLog::start(connection.clone(), retention).expect("Failed to set up the query log");
 
     let udp_socket = UdpSocket::bind("127.0.0.1:2053")
         .await
         .expect("Failed to bind to localhost address");
-    let resolver_socket = Arc::new(
-        UdpSocket::bind("0.0.0.0:0")
-            .await
-            .expect("Failed to bind to resolver address"),
+    let tcp_listener = TcpListener::bind("127.0.0.1:2053")
+        .await
+        .expect("Failed to bind to localhost address");
+    let retry = RetryPolicy {
+        timeout: Duration::from_millis(args.upstream_timeout),
+        attempts: args.upstream_attempts.max(1),
+        backoff: Duration::from_millis(args.upstream_backoff),
+    };
+    let tls_config = tls::client_config(args.upstream_ca_file.as_deref())
+        .expect("Failed to load upstream CA bundle");
+    let upstreams = Arc::new(
+        UpstreamPool::new(
+            &args.resolver,
+            args.strategy,
+            retry,
+            tls_config,
+            args.doh_method,
+        )
+        .await
+        .expect("Failed to set up upstream resolvers"),
     );
+    let cache = Arc::new(Cache::new(
+        args.cache_size,
+        Duration::from_secs(args.max_stale),
+    ));
+    let forwarder = Arc::new(Forwarder::new(upstreams.clone(), cache.clone(), query_log));
+    let limits = StreamLimits {
+        idle_timeout: Duration::from_secs(args.tcp_idle_timeout),
+        max_connections: args.tcp_max_connections,
+    };
+    tokio::spawn(server::serve_tcp(tcp_listener, forwarder.clone(), limits));
 
-    tokio::spawn(async move {
-        let listener = TcpListener::bind("0.0.0.0:80").unwrap();
-
-        for stream in listener.incoming() {
-            match stream {
-                Ok(mut stream) => {
-                    std::thread::spawn(move || {
-                        let mut buf: [u8; 256] = [0; 256];
-                        if let Ok(message_length) = stream.read(&mut buf) {
-                            let message = parse_request(&buf, message_length);
-
-                            if message.path == "/" {
-                                let request_count: i64 = connection
-                                    .prepare("SELECT COUNT(*) FROM queries")
-                                    .unwrap()
-                                    .read(0)
-                                    .unwrap();
-
-                                let response_body = format!("There have been {} requests", request_count);
-                                let response = Response {
-                                    status_code: 200,
-                                    headers: vec![
-                                        "Content-Type: text/plain".to_owned(),
-                                        format!("Content-Length: {}", response_body.len()),
-                                    ],
-                                    body: response_body,
-                                };
-                                stream.write(response.to_string().as_bytes()).unwrap();
-                            }
-                        }
-                    });
-                }
-                _ => {}
-            }
-        }
-    });
-
-    let mut buf = [0; 512];
-
-    loop {
-        match udp_socket.recv_from(&mut buf).await {
-            Ok((_, request_source)) => {
-                let mut dns_query = DnsQuery::deserialize(&buf);
-                println!("Request: {:?}", dns_query);
-
-                let singular_queries = dns_query.split_questions();
-
-                let mut tasks = vec![];
-
-                for query in singular_queries {
-                    let resolver_socket = resolver_socket.clone();
-                    tasks.push(tokio::spawn(async move {
-                        resolver_socket
-                            .send_to(&query.serialize(), resolver)
-                            .await
-                            .expect("Failed to send request to resolver");
-                        resolver_socket.recv(&mut buf).await.unwrap();
-                        DnsResponse::deserialize(&buf)
-                    }));
-                }
-
-                let responses = join_all(tasks).await;
-                let mut header = responses[0].as_ref().unwrap().header.clone();
-                let mut answers = vec![];
-                for response in responses {
-                    for answer in response.unwrap().answers {
-                        answers.push(answer);
-                    }
-                }
-                header.qdcount = dns_query.questions.len() as u16;
-                header.ancount = answers.len() as u16;
-                let response = DnsResponse {
-                    header,
-                    questions: dns_query.questions,
-                    answers,
-                };
-                udp_socket
-                    .send_to(&response.serialize(), request_source)
-                    .await
-                    .expect("Failed to send response to client");
-                println!("Responded: {:?}", response);
-            }
-            Err(e) => {
-                eprintln!("Error receiving data: {}", e);
-                break;
-            }
-        }
+    if let (Some(port), Some(cert), Some(key)) = (args.dot_port, &args.tls_cert, &args.tls_key) {
+        let server_config =
+            tls::server_config(cert, key).expect("Failed to load TLS certificate and key");
+        let dot_listener = TcpListener::bind(("0.0.0.0", port))
+            .await
+            .expect("Failed to bind to DNS-over-TLS address");
+        tokio::spawn(server::serve_tls(
+            dot_listener,
+            TlsAcceptor::from(server_config),
+            forwarder.clone(),
+            limits,
+        ));
     }
-}
 
-fn parse_request(request: &[u8; 256], message_length: usize) -> Request {
-    let request = &request[0..message_length];
-
-    let header_section = String::from_utf8_lossy(request);
-
-    let mut lines = header_section.lines();
-    let first_line = lines.next().unwrap();
-    let mut parts = first_line.split_whitespace();
-    let method = parts.next().unwrap().to_owned();
-    let path = parts.next().unwrap().to_owned();
-    let http_version = parts.next().unwrap().to_owned();
-
-    let mut headers = Vec::new();
-
-    let mut parsing_headers = true;
-    let mut content_length: Option<usize> = None;
-    let mut content = String::new();
-    for line in lines {
-        if parsing_headers {
-            if line.is_empty() {
-                parsing_headers = false;
-            } else {
-                if line.starts_with("Content-Length") {
-                    content_length = line.split(":").collect::<Vec<&str>>()[1]
-                        .trim()
-                        .parse::<usize>()
-                        .ok();
-                }
-                headers.push(line.to_owned());
-            }
-        } else if content_length.is_some() {
-            content.push_str(line);
-        }
+    let http_context = Arc::new(HttpContext {
+        connection,
+        upstreams,
+        cache,
+        forwarder: forwarder.clone(),
+    });
+    let http_listener = TcpListener::bind(args.http_addr)
+        .await
+        .expect("Failed to bind to HTTP address");
+    tokio::spawn(http::serve_http(
+        http_listener,
+        http_context.clone(),
+        args.tcp_max_connections,
+    ));
+
+    if let (Some(port), Some(cert), Some(key)) = (args.doh_port, &args.tls_cert, &args.tls_key) {
+        let mut server_config = (*tls::server_config(cert, key)
+            .expect("Failed to load TLS certificate and key"))
+        .clone();
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let doh_listener = TcpListener::bind(("0.0.0.0", port))
+            .await
+            .expect("Failed to bind to DNS-over-HTTPS address");
+        tokio::spawn(http::serve_https(
+            doh_listener,
+            TlsAcceptor::from(Arc::new(server_config)),
+            http_context,
+            args.tcp_max_connections,
+        ));
     }
 
-    return Request {
-        method,
-        headers,
-        path,
-        http_version,
-        content: content.to_owned(),
-    };
-}
-
-#[allow(dead_code)]
-struct Request {
-    method: String,
-    headers: Vec<String>,
-    path: String,
-    http_version: String,
-    content: String,
+    server::serve_udp(udp_socket, forwarder).await;
 }
-
-struct Response {
-    status_code: u16,
-    headers: Vec<String>,
-    body: String,
-}
-
-impl Display for Response {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut response = format!("HTTP/1.1 {}\r\n", self.status_code);
-        self.headers.iter().for_each(|header| {
-            response.push_str(&format!("{}\r\n", header));
-        });
-        response.push_str("\r\n");
-        response.push_str(&self.body);
-        return write!(f, "{}", response);
-    }
-}
\ No newline at end of file
@@ -0,0 +1,599 @@
+use crate::dns::{RecordType, ResponseCode};
+use sqlite::Value;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+/// How many records may wait to be written before new ones are dropped.
+const QUEUE_LENGTH: usize = 10_000;
+/// The most records written in one transaction.
+const BATCH_SIZE: usize = 500;
+
+/// How often old records are pruned.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The steps that build up the schema, in order. `PRAGMA user_version` records how many of
+/// them a database has had, so only new ones run when it is opened again.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE queries (
+        time TEXT NOT NULL,
+        client TEXT NOT NULL,
+        qname TEXT NOT NULL,
+        qtype TEXT NOT NULL,
+        rcode TEXT NOT NULL,
+        answers INTEGER NOT NULL,
+        upstream TEXT,
+        cache_hit INTEGER NOT NULL,
+        latency_ms REAL NOT NULL
+    );
+    ",
+    "
+    CREATE INDEX queries_time ON queries (time);
+    CREATE INDEX queries_qname ON queries (qname);
+    CREATE INDEX queries_client ON queries (client);
+    ",
+];
+
+const INSERT: &str = "
+    INSERT INTO queries
+        (time, client, qname, qtype, rcode, answers, upstream, cache_hit, latency_ms)
+    VALUES
+        (strftime('%Y-%m-%dT%H:%M:%fZ', ?, 'unixepoch'), ?, ?, ?, ?, ?, ?, ?, ?)
+";
+
+/// One handled query, as it goes into the `queries` table.
+#[derive(Debug, Clone)]
+pub struct QueryRecord {
+    pub time: SystemTime,
+    pub client: SocketAddr,
+    pub qname: String,
+    pub qtype: RecordType,
+    pub rcode: ResponseCode,
+    pub answers: usize,
+    /// The upstream that answered, if the answer didn't come from the cache.
+    pub upstream: Option<String>,
+    pub cache_hit: bool,
+    pub latency: Duration,
+}
+
+/// A query as read back from the `queries` table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoggedQuery {
+    pub time: String,
+    pub client: String,
+    pub qname: String,
+    pub qtype: String,
+    pub rcode: String,
+    pub answers: i64,
+    pub upstream: Option<String>,
+    pub cache_hit: bool,
+    pub latency_ms: f64,
+}
+
+/// Which logged queries to look for. Filters that are `None` match everything.
+#[derive(Debug, Clone, Default)]
+pub struct QueryFilter {
+    /// A client `IP:PORT`, or just an IP to match any port.
+    pub client: Option<String>,
+    /// Part of the name asked about, in any case.
+    pub qname: Option<String>,
+    pub qtype: Option<String>,
+    pub rcode: Option<String>,
+    /// The earliest time to include, in any format SQLite understands.
+    pub since: Option<String>,
+    /// The time to stop before, in any format SQLite understands.
+    pub until: Option<String>,
+    pub limit: u32,
+    pub offset: u64,
+}
+
+/// One page of matching queries, newest first, and how many matched in all.
+#[derive(Debug)]
+pub struct QueryPage {
+    pub total: i64,
+    pub queries: Vec<LoggedQuery>,
+}
+
+#[derive(Debug, Error)]
+pub enum SearchError {
+    #[error("invalid time {0:?}")]
+    InvalidTime(String),
+    #[error("offset {0} is too large")]
+    InvalidOffset(u64),
+    #[error("failed to read the query log: {0}")]
+    Database(#[from] sqlite::Error),
+}
+
+/// How much of the query log to keep. Older records, and the oldest ones past the row limit,
+/// are pruned periodically.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    pub max_rows: Option<u64>,
+}
+
+/// Writes handled queries to the `queries` table. Records are queued and written in batches by
+/// a background task, so logging never holds up a reply.
+#[derive(Clone)]
+pub struct QueryLog {
+    sender: mpsc::Sender<QueryRecord>,
+}
+
+impl QueryLog {
+    /// Brings the database's schema up to date and starts the background writer, along with a
+    /// pruning task if `retention` sets any limits.
+    pub fn start(
+        connection: Arc<Mutex<sqlite::Connection>>,
+        retention: Retention,
+    ) -> sqlite::Result<QueryLog> {
+        migrate(&connection.lock().unwrap())?;
+        let (sender, receiver) = mpsc::channel(QUEUE_LENGTH);
+        if retention.max_age.is_some() || retention.max_rows.is_some() {
+            tokio::spawn(prune_periodically(connection.clone(), retention));
+        }
+        tokio::spawn(write_batches(connection, receiver));
+        return Ok(QueryLog { sender });
+    }
+
+    /// Queues a record for writing. If the writer has fallen too far behind the record is
+    /// dropped rather than making the caller wait.
+    pub fn record(&self, record: QueryRecord) {
+        if let Err(TrySendError::Full(record)) = self.sender.try_send(record) {
+            eprintln!("Query log is backed up, dropping {}", record.qname);
+        }
+    }
+}
+
+/// Runs whichever migrations the database hasn't had yet, each in its own transaction.
+fn migrate(connection: &sqlite::Connection) -> sqlite::Result<()> {
+    let mut statement = connection.prepare("PRAGMA user_version")?;
+    statement.next()?;
+    let version = statement.read::<i64, _>(0)? as usize;
+    drop(statement);
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        connection.execute("BEGIN")?;
+        let result = connection.execute(format!(
+            "{} PRAGMA user_version = {};",
+            migration,
+            index + 1
+        ));
+        match result {
+            Ok(()) => connection.execute("COMMIT")?,
+            Err(e) => {
+                let _ = connection.execute("ROLLBACK");
+                return Err(e);
+            }
+        }
+    }
+    return Ok(());
+}
+
+async fn prune_periodically(connection: Arc<Mutex<sqlite::Connection>>, retention: Retention) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let connection = connection.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            return prune(&connection.lock().unwrap(), retention, SystemTime::now());
+        })
+        .await
+        .unwrap();
+        if let Err(e) = result {
+            eprintln!("Failed to prune query log: {}", e);
+        }
+    }
+}
+
+/// Deletes the records that `retention` no longer allows, as of `now`.
+fn prune(
+    connection: &sqlite::Connection,
+    retention: Retention,
+    now: SystemTime,
+) -> sqlite::Result<()> {
+    // An age reaching back past the start of the clock keeps everything.
+    let cutoff = retention
+        .max_age
+        .and_then(|max_age| now.checked_sub(max_age));
+    if let Some(cutoff) = cutoff {
+        let mut statement = connection.prepare(
+            "DELETE FROM queries WHERE time < strftime('%Y-%m-%dT%H:%M:%fZ', ?, 'unixepoch')",
+        )?;
+        statement.bind((1, unix_time(cutoff)))?;
+        statement.next()?;
+    }
+    if let Some(max_rows) = retention.max_rows {
+        let mut statement = connection.prepare(
+            "DELETE FROM queries
+            WHERE rowid <= (SELECT rowid FROM queries ORDER BY rowid DESC LIMIT 1 OFFSET ?)",
+        )?;
+        // SQLite takes a signed OFFSET; any limit beyond it keeps everything anyway.
+        statement.bind((1, i64::try_from(max_rows).unwrap_or(i64::MAX)))?;
+        statement.next()?;
+    }
+    return Ok(());
+}
+
+/// Seconds since the Unix epoch, which is how times are handed to SQLite.
+fn unix_time(time: SystemTime) -> f64 {
+    return time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+}
+
+/// Looks up the logged queries matching `filter`.
+pub fn search(
+    connection: &sqlite::Connection,
+    filter: &QueryFilter,
+) -> Result<QueryPage, SearchError> {
+    let mut conditions = vec![];
+    let mut params = vec![];
+    if let Some(client) = &filter.client {
+        match (client.parse::<SocketAddr>(), client.parse::<IpAddr>()) {
+            (Err(_), Ok(IpAddr::V4(ip))) => {
+                conditions.push("client LIKE ?");
+                params.push(Value::String(format!("{}:%", ip)));
+            }
+            (Err(_), Ok(IpAddr::V6(ip))) => {
+                conditions.push("client LIKE ?");
+                params.push(Value::String(format!("[{}]:%", ip)));
+            }
+            _ => {
+                conditions.push("client = ?");
+                params.push(Value::String(client.clone()));
+            }
+        }
+    }
+    if let Some(qname) = &filter.qname {
+        conditions.push("qname LIKE ? ESCAPE '\\'");
+        let escaped = qname
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        params.push(Value::String(format!("%{}%", escaped)));
+    }
+    if let Some(qtype) = &filter.qtype {
+        conditions.push("qtype = ?");
+        params.push(Value::String(qtype.to_uppercase()));
+    }
+    if let Some(rcode) = &filter.rcode {
+        conditions.push("rcode = ?");
+        params.push(Value::String(rcode.to_uppercase()));
+    }
+    if let Some(since) = &filter.since {
+        conditions.push("time >= ?");
+        params.push(Value::String(normalize_time(connection, since)?));
+    }
+    if let Some(until) = &filter.until {
+        conditions.push("time < ?");
+        params.push(Value::String(normalize_time(connection, until)?));
+    }
+    let condition = match conditions.is_empty() {
+        true => String::new(),
+        false => format!("WHERE {}", conditions.join(" AND ")),
+    };
+
+    let mut statement =
+        connection.prepare(format!("SELECT COUNT(*) FROM queries {}", condition))?;
+    statement.bind(&params[..])?;
+    statement.next()?;
+    let total = statement.read::<i64, _>(0)?;
+
+    let mut statement = connection.prepare(format!(
+        "SELECT time, client, qname, qtype, rcode, answers, upstream, cache_hit, latency_ms
+        FROM queries {} ORDER BY time DESC, rowid DESC LIMIT ? OFFSET ?",
+        condition
+    ))?;
+    statement.bind(&params[..])?;
+    let offset =
+        i64::try_from(filter.offset).map_err(|_| SearchError::InvalidOffset(filter.offset))?;
+    statement.bind((params.len() + 1, i64::from(filter.limit)))?;
+    statement.bind((params.len() + 2, offset))?;
+    let mut queries = vec![];
+    while statement.next()? == sqlite::State::Row {
+        queries.push(LoggedQuery {
+            time: statement.read(0)?,
+            client: statement.read(1)?,
+            qname: statement.read(2)?,
+            qtype: statement.read(3)?,
+            rcode: statement.read(4)?,
+            answers: statement.read(5)?,
+            upstream: statement.read(6)?,
+            cache_hit: statement.read::<i64, _>(7)? != 0,
+            latency_ms: statement.read(8)?,
+        });
+    }
+    return Ok(QueryPage { total, queries });
+}
+
+/// Converts a time to the format the `time` column is stored in, so that they compare.
+fn normalize_time(connection: &sqlite::Connection, time: &str) -> Result<String, SearchError> {
+    let mut statement = connection.prepare("SELECT strftime('%Y-%m-%dT%H:%M:%fZ', ?)")?;
+    statement.bind((1, time))?;
+    statement.next()?;
+    return statement
+        .read::<Option<String>, _>(0)?
+        .ok_or_else(|| SearchError::InvalidTime(time.to_owned()));
+}
+
+async fn write_batches(
+    connection: Arc<Mutex<sqlite::Connection>>,
+    mut receiver: mpsc::Receiver<QueryRecord>,
+) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while receiver.recv_many(&mut batch, BATCH_SIZE).await > 0 {
+        let connection = connection.clone();
+        let records = std::mem::take(&mut batch);
+        let result = tokio::task::spawn_blocking(move || {
+            return write_batch(&connection.lock().unwrap(), &records);
+        })
+        .await
+        .unwrap();
+        if let Err(e) = result {
+            eprintln!("Failed to write query log: {}", e);
+        }
+    }
+}
+
+/// Inserts `records` in a single transaction.
+fn write_batch(connection: &sqlite::Connection, records: &[QueryRecord]) -> sqlite::Result<()> {
+    connection.execute("BEGIN")?;
+    let result = insert_all(connection, records);
+    return match result {
+        Ok(()) => connection.execute("COMMIT"),
+        Err(e) => {
+            let _ = connection.execute("ROLLBACK");
+            Err(e)
+        }
+    };
+}
+
+fn insert_all(connection: &sqlite::Connection, records: &[QueryRecord]) -> sqlite::Result<()> {
+    let mut statement = connection.prepare(INSERT)?;
+    for record in records {
+        statement.reset()?;
+        statement.bind((1, unix_time(record.time)))?;
+        statement.bind((2, record.client.to_string().as_str()))?;
+        statement.bind((3, record.qname.as_str()))?;
+        statement.bind((4, record.qtype.to_string().as_str()))?;
+        statement.bind((5, record.rcode.to_string().as_str()))?;
+        statement.bind((6, record.answers as i64))?;
+        statement.bind((7, record.upstream.as_deref()))?;
+        statement.bind((8, record.cache_hit as i64))?;
+        statement.bind((9, record.latency.as_secs_f64() * 1000.0))?;
+        statement.next()?;
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIME: Duration = Duration::from_millis(1_700_000_000_250);
+
+    fn record(qname: &str) -> QueryRecord {
+        return QueryRecord {
+            time: UNIX_EPOCH + TIME,
+            client: "127.0.0.1:5353".parse().unwrap(),
+            qname: qname.to_owned(),
+            qtype: RecordType::AAAA,
+            rcode: ResponseCode::NXDomain,
+            answers: 0,
+            upstream: Some("127.0.0.1:53".to_owned()),
+            cache_hit: false,
+            latency: Duration::from_micros(1500),
+        };
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let connection = sqlite::open(":memory:").unwrap();
+        migrate(&connection).unwrap();
+        write_batch(&connection, &[record("a.example"), record("b.example")]).unwrap();
+
+        let mut statement = connection
+            .prepare("SELECT time, qname, qtype, rcode, upstream, latency_ms FROM queries")
+            .unwrap();
+        assert_eq!(statement.next().unwrap(), sqlite::State::Row);
+        assert_eq!(
+            statement.read::<String, _>(0).unwrap(),
+            "2023-11-14T22:13:20.250Z"
+        );
+        assert_eq!(statement.read::<String, _>(1).unwrap(), "a.example");
+        assert_eq!(statement.read::<String, _>(2).unwrap(), "AAAA");
+        assert_eq!(statement.read::<String, _>(3).unwrap(), "NXDOMAIN");
+        assert_eq!(statement.read::<String, _>(4).unwrap(), "127.0.0.1:53");
+        assert_eq!(statement.read::<f64, _>(5).unwrap(), 1.5);
+        assert_eq!(statement.next().unwrap(), sqlite::State::Row);
+        assert_eq!(statement.next().unwrap(), sqlite::State::Done);
+    }
+
+    /// The first column of the first row `sql` returns.
+    fn select<T: sqlite::ReadableWithIndex>(connection: &sqlite::Connection, sql: &str) -> T {
+        let mut statement = connection.prepare(sql).unwrap();
+        statement.next().unwrap();
+        return statement.read(0).unwrap();
+    }
+
+    fn count(connection: &sqlite::Connection) -> i64 {
+        return select(connection, "SELECT COUNT(*) FROM queries");
+    }
+
+    #[test]
+    fn test_migrate_persists_schema() {
+        let path = std::env::temp_dir().join(format!("dns-rust-{}-queries.db", std::process::id()));
+        {
+            let connection = sqlite::open(&path).unwrap();
+            migrate(&connection).unwrap();
+            write_batch(&connection, &[record("example.com.")]).unwrap();
+        }
+        // Reopening must keep the data and not try to create the table again.
+        let connection = sqlite::open(&path).unwrap();
+        migrate(&connection).unwrap();
+        assert_eq!(count(&connection), 1);
+
+        let indexes: i64 = select(
+            &connection,
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND tbl_name = 'queries'",
+        );
+        assert_eq!(indexes, 3);
+        let version: i64 = select(&connection, "PRAGMA user_version");
+        assert_eq!(version, MIGRATIONS.len() as i64);
+
+        drop(connection);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_prune() {
+        let connection = sqlite::open(":memory:").unwrap();
+        migrate(&connection).unwrap();
+        let mut records: Vec<QueryRecord> = (0..10).map(|_| record("example.com.")).collect();
+        for (minutes, record) in records.iter_mut().enumerate() {
+            record.time += Duration::from_secs(60 * minutes as u64);
+        }
+        write_batch(&connection, &records).unwrap();
+        let now = UNIX_EPOCH + TIME + Duration::from_secs(60 * 10);
+
+        let forever = Retention {
+            max_age: Some(Duration::MAX),
+            max_rows: None,
+        };
+        prune(&connection, forever, now).unwrap();
+        assert_eq!(count(&connection), 10);
+
+        let unlimited = Retention {
+            max_age: None,
+            max_rows: Some(u64::MAX),
+        };
+        prune(&connection, unlimited, now).unwrap();
+        assert_eq!(count(&connection), 10);
+
+        let by_age = Retention {
+            max_age: Some(Duration::from_secs(60 * 4)),
+            max_rows: None,
+        };
+        prune(&connection, by_age, now).unwrap();
+        assert_eq!(count(&connection), 4);
+
+        let by_rows = Retention {
+            max_age: None,
+            max_rows: Some(3),
+        };
+        prune(&connection, by_rows, now).unwrap();
+        assert_eq!(count(&connection), 3);
+        let oldest: String = select(&connection, "SELECT MIN(time) FROM queries");
+        assert_eq!(oldest, "2023-11-14T22:20:20.250Z");
+    }
+
+    #[test]
+    fn test_search() {
+        let connection = sqlite::open(":memory:").unwrap();
+        migrate(&connection).unwrap();
+        let mut records = vec![];
+        for (minutes, (client, qname, qtype)) in [
+            ("127.0.0.1:5353", "www.example.com.", RecordType::A),
+            ("127.0.0.1:6000", "mail.example.com.", RecordType::AAAA),
+            ("[::1]:5353", "www.example.org.", RecordType::A),
+            ("10.0.0.1:5353", "under_score.example.", RecordType::A),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let mut record = record(qname);
+            record.time += Duration::from_secs(60 * minutes as u64);
+            record.client = client.parse().unwrap();
+            record.qtype = qtype;
+            records.push(record);
+        }
+        records[3].upstream = None;
+        records[3].cache_hit = true;
+        write_batch(&connection, &records).unwrap();
+
+        let find = |filter: QueryFilter| {
+            let page = search(
+                &connection,
+                &QueryFilter {
+                    limit: 10,
+                    ..filter
+                },
+            )
+            .unwrap();
+            let qnames: Vec<String> = page.queries.into_iter().map(|q| q.qname).collect();
+            return (page.total, qnames);
+        };
+        let (total, qnames) = find(QueryFilter::default());
+        assert_eq!(total, 4);
+        assert_eq!(qnames[0], "under_score.example.");
+
+        let client = |client: &str| QueryFilter {
+            client: Some(client.to_owned()),
+            ..QueryFilter::default()
+        };
+        assert_eq!(find(client("127.0.0.1")).0, 2);
+        assert_eq!(find(client("127.0.0.1:6000")).0, 1);
+        assert_eq!(find(client("::1")).0, 1);
+
+        let qname = |qname: &str| QueryFilter {
+            qname: Some(qname.to_owned()),
+            ..QueryFilter::default()
+        };
+        assert_eq!(find(qname("EXAMPLE.COM")).0, 2);
+        // `_` is a LIKE wildcard, so it has to be escaped to only match itself.
+        assert_eq!(find(qname("w_w")).0, 0);
+        assert_eq!(find(qname("under_")).0, 1);
+
+        let filter = QueryFilter {
+            qtype: Some("a".to_owned()),
+            since: Some("2023-11-14 22:14:00".to_owned()),
+            until: Some("2023-11-14T22:16:20.250Z".to_owned()),
+            ..QueryFilter::default()
+        };
+        assert_eq!(find(filter), (1, vec!["www.example.org.".to_owned()]));
+
+        let page = search(
+            &connection,
+            &QueryFilter {
+                limit: 2,
+                offset: 1,
+                ..QueryFilter::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(page.total, 4);
+        assert_eq!(page.queries.len(), 2);
+        assert_eq!(page.queries[0].qname, "www.example.org.");
+        assert_eq!(page.queries[0].upstream.as_deref(), Some("127.0.0.1:53"));
+        assert!(!page.queries[0].cache_hit);
+
+        let filter = QueryFilter {
+            since: Some("yesterday".to_owned()),
+            ..QueryFilter::default()
+        };
+        assert!(matches!(
+            search(&connection, &filter),
+            Err(SearchError::InvalidTime(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_records_are_written_in_the_background() {
+        let connection = Arc::new(Mutex::new(sqlite::open(":memory:").unwrap()));
+        let log = QueryLog::start(connection.clone(), Retention::default()).unwrap();
+        for _ in 0..3 {
+            log.record(record("example.com"));
+        }
+        for _ in 0..100 {
+            if count(&connection.lock().unwrap()) == 3 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("records were never written");
+    }
+}
@@ -0,0 +1,291 @@
+use crate::forwarder::{Forwarder, Transport};
+use crate::framing::{frame, read_message};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{mpsc, Semaphore};
+use tokio_rustls::TlsAcceptor;
+
+/// How long to pause after a failed `accept`, which usually means we are out of file
+/// descriptors, before trying again.
+pub const ACCEPT_ERROR_PAUSE: Duration = Duration::from_millis(100);
+/// How many pipelined queries one connection may have in flight; we stop reading from it until
+/// one of them is answered.
+const MAX_PIPELINED_QUERIES: usize = 16;
+
+/// Answers plain DNS over UDP, one task per datagram.
+pub async fn serve_udp(socket: UdpSocket, forwarder: Arc<Forwarder>) {
+    let socket = Arc::new(socket);
+    let mut buf = [0; 4096];
+
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((len, request_source)) => {
+                let request = buf[..len].to_vec();
+                let socket = socket.clone();
+                let forwarder = forwarder.clone();
+                tokio::spawn(async move {
+                    let response = forwarder
+                        .handle(&request, request_source, Transport::Udp)
+                        .await;
+                    if let Some(response) = response {
+                        if let Err(e) = socket.send_to(&response, request_source).await {
+                            eprintln!("Failed to send response to {}: {}", request_source, e);
+                        }
+                    }
+                });
+            }
+            Err(e) => {
+                eprintln!("Error receiving data: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Limits applied to each stream-based listener.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamLimits {
+    /// How long a connection may sit without a new query before we close it.
+    pub idle_timeout: Duration,
+    /// How many connections may be open at once; any more are closed straight away.
+    pub max_connections: usize,
+}
+
+/// Answers plain DNS over TCP.
+pub async fn serve_tcp(listener: TcpListener, forwarder: Arc<Forwarder>, limits: StreamLimits) {
+    let connections = Arc::new(Semaphore::new(limits.max_connections));
+
+    loop {
+        let (stream, client) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Error accepting TCP connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_PAUSE).await;
+                continue;
+            }
+        };
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            eprintln!(
+                "Refusing TCP connection from {}: too many connections",
+                client
+            );
+            continue;
+        };
+        let forwarder = forwarder.clone();
+        tokio::spawn(async move {
+            serve_stream(stream, client, forwarder, limits.idle_timeout).await;
+            drop(permit);
+        });
+    }
+}
+
+/// Answers DNS over TLS (RFC 7858): the same framing as TCP, inside a TLS session.
+pub async fn serve_tls(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    forwarder: Arc<Forwarder>,
+    limits: StreamLimits,
+) {
+    let connections = Arc::new(Semaphore::new(limits.max_connections));
+
+    loop {
+        let (stream, client) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Error accepting TLS connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_PAUSE).await;
+                continue;
+            }
+        };
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            eprintln!(
+                "Refusing TLS connection from {}: too many connections",
+                client
+            );
+            continue;
+        };
+        let acceptor = acceptor.clone();
+        let forwarder = forwarder.clone();
+        tokio::spawn(async move {
+            // A client that stalls the handshake is as idle as one that stops sending queries.
+            match tokio::time::timeout(limits.idle_timeout, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    serve_stream(stream, client, forwarder, limits.idle_timeout).await;
+                }
+                Ok(Err(e)) => eprintln!("TLS handshake with {} failed: {}", client, e),
+                Err(_) => eprintln!("TLS handshake with {} timed out", client),
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Serves one connection using the two-byte length framing of RFC 1035 section 4.2.2. Queries
+/// may be pipelined, up to `MAX_PIPELINED_QUERIES` at a time; each is answered as soon as it is
+/// resolved, which may be out of order, as RFC 7766 allows.
+pub async fn serve_stream<S>(
+    stream: S,
+    client: SocketAddr,
+    forwarder: Arc<Forwarder>,
+    idle_timeout: Duration,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (responses, mut pending) = mpsc::channel::<Vec<u8>>(MAX_PIPELINED_QUERIES);
+    let in_flight = Arc::new(Semaphore::new(MAX_PIPELINED_QUERIES));
+
+    let write_task = tokio::spawn(async move {
+        while let Some(response) = pending.recv().await {
+            if writer.write_all(&frame(&response)).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    loop {
+        let request = match tokio::time::timeout(idle_timeout, read_message(&mut reader)).await {
+            Ok(Ok(Some(request))) => request,
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => {
+                eprintln!("Error reading from {}: {}", client, e);
+                break;
+            }
+            Err(_) => break,
+        };
+        let permit = in_flight.clone().acquire_owned().await.unwrap();
+        let forwarder = forwarder.clone();
+        let responses = responses.clone();
+        tokio::spawn(async move {
+            let response = forwarder.handle(&request, client, Transport::Stream).await;
+            if let Some(response) = response {
+                let _ = responses.send(response).await;
+            }
+            drop(permit);
+        });
+    }
+
+    // The writer finishes once every in-flight query has dropped its sender.
+    drop(responses);
+    let _ = write_task.await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::read_message;
+    use crate::querylog::{QueryLog, Retention};
+    use crate::upstream::{DohMethod, RetryPolicy, Strategy, UpstreamPool};
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use rustls::{ClientConfig, RootCertStore, ServerConfig};
+    use std::sync::Mutex;
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+
+    /// A forwarder whose upstream never answers, for tests that don't need one.
+    async fn forwarder() -> Arc<Forwarder> {
+        let retry = RetryPolicy {
+            timeout: Duration::from_millis(10),
+            attempts: 1,
+            backoff: Duration::ZERO,
+        };
+        let upstreams = UpstreamPool::new(
+            &["127.0.0.1:9".parse().unwrap()],
+            Strategy::Order,
+            retry,
+            crate::tls::client_config(None).unwrap(),
+            DohMethod::Post,
+        )
+        .await
+        .unwrap();
+        return Arc::new(Forwarder::new(
+            Arc::new(upstreams),
+            Arc::new(crate::cache::Cache::new(100, Duration::ZERO)),
+            QueryLog::start(
+                Arc::new(Mutex::new(sqlite::open(":memory:").unwrap())),
+                Retention::default(),
+            )
+            .unwrap(),
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_serve_stream_pipelined() {
+        let forwarder = forwarder().await;
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handle = tokio::spawn(serve_stream(
+            server,
+            "127.0.0.1:5353".parse().unwrap(),
+            forwarder,
+            Duration::from_secs(1),
+        ));
+
+        // Question-less queries, which are answered with FORMERR without going upstream. There
+        // are more than may be in flight at once, so some wait for others to finish.
+        let count = MAX_PIPELINED_QUERIES as u8 * 2;
+        for id in 0..count {
+            client
+                .write_all(&[0, 12, 0, id, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        }
+        let mut ids = vec![];
+        for _ in 0..count {
+            let response = read_message(&mut client).await.unwrap().unwrap();
+            assert_eq!(response.len(), 12);
+            assert_eq!(response[3] & 0b1111, 1);
+            ids.push(response[1]);
+        }
+        ids.sort();
+        assert_eq!(ids, (0..count).collect::<Vec<_>>());
+
+        drop(client);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serve_tls() {
+        let certified = rcgen::generate_simple_self_signed(vec!["dns.test".to_owned()]).unwrap();
+        let cert = CertificateDer::from(certified.cert.der().to_vec());
+        let key = PrivateKeyDer::try_from(certified.key_pair.serialize_der()).unwrap();
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = StreamLimits {
+            idle_timeout: Duration::from_secs(1),
+            max_connections: 1,
+        };
+        tokio::spawn(serve_tls(
+            listener,
+            TlsAcceptor::from(Arc::new(server_config)),
+            forwarder().await,
+            limits,
+        ));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let mut client = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("dns.test").unwrap(), tcp)
+            .await
+            .unwrap();
+
+        client
+            .write_all(&frame(&[0, 7, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]))
+            .await
+            .unwrap();
+        let response = read_message(&mut client).await.unwrap().unwrap();
+        assert_eq!(response[1], 7);
+        assert_eq!(response[3] & 0b1111, 1);
+    }
+}
@@ -0,0 +1,85 @@
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The TLS settings for talking to upstreams. Certificates are checked against the Mozilla root
+/// store, or only against the certificates in `ca_file` if one is given.
+pub fn client_config(ca_file: Option<&Path>) -> std::io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            for cert in load_certs(path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    return Ok(Arc::new(config));
+}
+
+/// The TLS settings for our own listeners, presenting the certificate chain in `cert_file` with
+/// the private key in `key_file`.
+pub fn server_config(cert_file: &Path, key_file: &Path) -> std::io::Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert_file)?;
+    let key = load_private_key(key_file)?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    return Ok(Arc::new(config));
+}
+
+/// Reads every certificate from a PEM file.
+pub fn load_certs(path: &Path) -> std::io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("no certificates found in {}", path.display()),
+        ));
+    }
+    return Ok(certs);
+}
+
+/// Reads the first private key from a PEM file.
+fn load_private_key(path: &Path) -> std::io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    return rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("no private key found in {}", path.display()),
+        )
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_config_from_pem() {
+        let certified = rcgen::generate_simple_self_signed(vec!["dns.test".to_owned()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_file = dir.join(format!("dns-rust-{}-server.crt", std::process::id()));
+        let key_file = dir.join(format!("dns-rust-{}-server.key", std::process::id()));
+        std::fs::write(&cert_file, certified.cert.pem()).unwrap();
+        std::fs::write(&key_file, certified.key_pair.serialize_pem()).unwrap();
+
+        assert!(server_config(&cert_file, &key_file).is_ok());
+        assert!(client_config(Some(&cert_file)).is_ok());
+        // The key file holds no certificates, and the certificate file no key.
+        assert!(server_config(&key_file, &cert_file).is_err());
+
+        std::fs::remove_file(cert_file).unwrap();
+        std::fs::remove_file(key_file).unwrap();
+    }
+}
@@ -0,0 +1,531 @@
+use crate::dns::{DnsQuery, DnsResponse, ParseError};
+use clap::ValueEnum;
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+mod https;
+mod tls;
+mod udp;
+
+pub use https::{DohEndpoint, DohMethod, HttpsUpstream, DNS_MESSAGE};
+pub use tls::TlsUpstream;
+pub use udp::UdpUpstream;
+
+/// Consecutive failures after which an upstream is benched.
+const FAILURES_BEFORE_BENCHING: u32 = 3;
+/// How long a benched upstream sits out before it is tried again.
+const BENCH_DURATION: Duration = Duration::from_secs(30);
+/// Weight given to the newest sample in the RTT moving average.
+const RTT_SMOOTHING: f64 = 0.3;
+
+#[derive(Debug, Error)]
+pub enum UpstreamError {
+    #[error("failed to send query upstream: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("upstream connection closed before replying")]
+    Closed,
+    #[error("no reply from upstream within {0:?}")]
+    Timeout(Duration),
+    #[error("malformed response from upstream: {0}")]
+    Malformed(#[from] ParseError),
+    #[error("upstream reply did not match the query")]
+    Mismatched,
+    #[error("HTTP error from upstream: {0}")]
+    Http(String),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error(
+    "invalid upstream {0:?}, expected IP[:PORT], tls://IP[:PORT][#NAME] or https://IP[:PORT][/PATH][#NAME]"
+)]
+pub struct SpecError(String);
+
+/// Where to forward queries and how to reach it, as given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpstreamSpec {
+    /// Plain DNS over UDP, written `IP[:PORT]` or `udp://IP[:PORT]`. Truncated replies are
+    /// retried over TCP to the same address.
+    Udp(SocketAddr),
+    /// DNS over TLS, written `tls://IP[:PORT][#NAME]`. The certificate must be valid for
+    /// `server_name`, which is also sent as SNI; without a `#NAME` it is checked against the IP.
+    Tls {
+        addr: SocketAddr,
+        server_name: String,
+    },
+    /// DNS over HTTPS, written `https://IP[:PORT][/PATH][#NAME]`: the resolver's URL with its
+    /// host replaced by an IP, and the hostname, if any, moved to the end.
+    Https(DohEndpoint),
+}
+
+impl FromStr for UpstreamSpec {
+    type Err = SpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SpecError(s.to_owned());
+        if s.starts_with("https://") {
+            return DohEndpoint::parse(s)
+                .map(UpstreamSpec::Https)
+                .ok_or_else(invalid);
+        }
+        if let Some(rest) = s.strip_prefix("tls://") {
+            let (addr, server_name) = match rest.split_once('#') {
+                Some((addr, name)) => (addr, Some(name)),
+                None => (rest, None),
+            };
+            let addr = parse_addr(addr, 853).ok_or_else(invalid)?;
+            let server_name = match server_name {
+                Some(name) => name.to_owned(),
+                None => addr.ip().to_string(),
+            };
+            ServerName::try_from(server_name.as_str()).map_err(|_| invalid())?;
+            return Ok(UpstreamSpec::Tls { addr, server_name });
+        }
+        let rest = s.strip_prefix("udp://").unwrap_or(s);
+        return parse_addr(rest, 53)
+            .map(UpstreamSpec::Udp)
+            .ok_or_else(invalid);
+    }
+}
+
+impl Display for UpstreamSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            UpstreamSpec::Udp(addr) => write!(f, "{}", addr),
+            UpstreamSpec::Tls { addr, server_name } => {
+                write!(f, "tls://{}#{}", addr, server_name)
+            }
+            UpstreamSpec::Https(endpoint) => write!(f, "{}", endpoint),
+        };
+    }
+}
+
+/// Parses `IP:PORT`, or a bare IP with the default port. Bare IPv6 addresses may be bracketed.
+fn parse_addr(s: &str, default_port: u16) -> Option<SocketAddr> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = s
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(s);
+    return ip
+        .parse::<IpAddr>()
+        .ok()
+        .map(|ip| SocketAddr::new(ip, default_port));
+}
+
+/// How hard to try an upstream before giving up on a query.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// How long to wait for each attempt's reply.
+    pub timeout: Duration,
+    /// Total number of attempts, including the first.
+    pub attempts: u32,
+    /// The pause after the first failed attempt, doubled after each further one.
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// The pause to take after the given (zero-based) attempt fails.
+    pub fn backoff_after(&self, attempt: u32) -> Duration {
+        return self.backoff.saturating_mul(1 << attempt.min(16));
+    }
+}
+
+/// How to pick which upstream a query goes to first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Strategy {
+    /// Always the first healthy upstream, in the order given.
+    Order,
+    /// Each upstream in turn.
+    RoundRobin,
+    /// A different random upstream each time.
+    Random,
+    /// The upstream with the lowest smoothed round-trip time.
+    Fastest,
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    queries: u64,
+    failures: u64,
+    consecutive_failures: u32,
+    /// Exponentially weighted moving average of the round-trip time, in seconds.
+    rtt: Option<f64>,
+    benched_until: Option<Instant>,
+}
+
+impl Health {
+    fn is_benched(&self, now: Instant) -> bool {
+        return self.benched_until.is_some_and(|until| until > now);
+    }
+
+    fn record_success(&mut self, rtt: Duration) {
+        self.queries += 1;
+        self.consecutive_failures = 0;
+        self.benched_until = None;
+        let sample = rtt.as_secs_f64();
+        self.rtt = Some(match self.rtt {
+            Some(average) => RTT_SMOOTHING * sample + (1.0 - RTT_SMOOTHING) * average,
+            None => sample,
+        });
+    }
+
+    fn record_failure(&mut self, now: Instant) {
+        self.queries += 1;
+        self.failures += 1;
+        self.consecutive_failures += 1;
+        if self.consecutive_failures >= FAILURES_BEFORE_BENCHING {
+            self.benched_until = Some(now + BENCH_DURATION);
+        }
+    }
+}
+
+enum Client {
+    Udp(UdpUpstream),
+    Tls(TlsUpstream),
+    Https(HttpsUpstream),
+}
+
+impl Client {
+    async fn query(&self, query: DnsQuery) -> Result<DnsResponse, UpstreamError> {
+        return match self {
+            Client::Udp(client) => client.query(query).await,
+            Client::Tls(client) => client.query(query).await,
+            Client::Https(client) => client.query(query).await,
+        };
+    }
+
+    /// Replies dropped as spoofed or unsolicited, for the transports that can receive those.
+    fn dropped_replies(&self) -> Option<u64> {
+        return match self {
+            Client::Udp(client) => Some(client.dropped_replies()),
+            Client::Tls(_) | Client::Https(_) => None,
+        };
+    }
+}
+
+struct Upstream {
+    spec: UpstreamSpec,
+    client: Client,
+    health: Mutex<Health>,
+}
+
+/// The set of upstream resolvers we forward to. Each attempt at a query goes to the next
+/// upstream the strategy picks, so retries fail over to a different resolver where there is
+/// one. Upstreams that keep failing are benched for a while and only used if every other
+/// upstream is benched too.
+pub struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    strategy: Strategy,
+    retry: RetryPolicy,
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    pub async fn new(
+        specs: &[UpstreamSpec],
+        strategy: Strategy,
+        retry: RetryPolicy,
+        tls_config: Arc<ClientConfig>,
+        doh_method: DohMethod,
+    ) -> std::io::Result<UpstreamPool> {
+        let mut upstreams = Vec::new();
+        for spec in specs {
+            let client = match spec {
+                UpstreamSpec::Udp(addr) => Client::Udp(UdpUpstream::new(*addr).await?),
+                UpstreamSpec::Tls { addr, server_name } => {
+                    Client::Tls(TlsUpstream::new(*addr, server_name, tls_config.clone())?)
+                }
+                UpstreamSpec::Https(endpoint) => Client::Https(HttpsUpstream::new(
+                    endpoint.clone(),
+                    doh_method,
+                    tls_config.clone(),
+                )),
+            };
+            upstreams.push(Upstream {
+                spec: spec.clone(),
+                client,
+                health: Mutex::new(Health::default()),
+            });
+        }
+        return Ok(UpstreamPool {
+            upstreams,
+            strategy,
+            retry,
+            next: AtomicUsize::new(0),
+        });
+    }
+
+    /// Sends a query upstream, retrying with backoff until it is answered or the attempts run
+    /// out. Returns the response along with the upstream that gave it.
+    pub async fn query(
+        &self,
+        query: DnsQuery,
+    ) -> Result<(DnsResponse, UpstreamSpec), UpstreamError> {
+        let candidates = self.candidates(Instant::now());
+        let mut attempt = 0;
+        loop {
+            let upstream = &self.upstreams[candidates[attempt as usize % candidates.len()]];
+            let started = Instant::now();
+            let result =
+                tokio::time::timeout(self.retry.timeout, upstream.client.query(query.clone()))
+                    .await;
+            let error = match result {
+                Ok(Ok(response)) => {
+                    upstream
+                        .health
+                        .lock()
+                        .unwrap()
+                        .record_success(started.elapsed());
+                    return Ok((response, upstream.spec.clone()));
+                }
+                Ok(Err(e)) => e,
+                Err(_) => UpstreamError::Timeout(self.retry.timeout),
+            };
+            upstream
+                .health
+                .lock()
+                .unwrap()
+                .record_failure(Instant::now());
+            attempt += 1;
+            if attempt >= self.retry.attempts {
+                return Err(error);
+            }
+            eprintln!(
+                "Attempt {} via {} failed, retrying: {}",
+                attempt, upstream.spec, error
+            );
+            tokio::time::sleep(self.retry.backoff_after(attempt - 1)).await;
+        }
+    }
+
+    /// Upstream indices in the order this query should try them: healthy ones as the strategy
+    /// orders them, then benched ones as a last resort.
+    fn candidates(&self, now: Instant) -> Vec<usize> {
+        let count = self.upstreams.len();
+        let mut order: Vec<usize> = match self.strategy {
+            Strategy::Order => (0..count).collect(),
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..count).map(|i| (start + i) % count).collect()
+            }
+            Strategy::Random => {
+                let start = rand::random::<usize>();
+                (0..count).map(|i| (start + i) % count).collect()
+            }
+            Strategy::Fastest => {
+                let rtts: Vec<f64> = self
+                    .upstreams
+                    .iter()
+                    // Untried upstreams sort first so that each gets measured.
+                    .map(|upstream| upstream.health.lock().unwrap().rtt.unwrap_or(0.0))
+                    .collect();
+                let mut order: Vec<usize> = (0..count).collect();
+                order.sort_by(|&a, &b| rtts[a].total_cmp(&rtts[b]));
+                order
+            }
+        };
+        // A stable sort keeps the strategy's order within the healthy and benched groups.
+        order.sort_by_key(|&i| self.upstreams[i].health.lock().unwrap().is_benched(now));
+        return order;
+    }
+}
+
+impl Display for UpstreamPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let now = Instant::now();
+        writeln!(f, "Upstreams ({:?}):", self.strategy)?;
+        for upstream in &self.upstreams {
+            let health = upstream.health.lock().unwrap();
+            let status = match health.benched_until {
+                Some(until) if until > now => {
+                    format!("benched for {}s", (until - now).as_secs() + 1)
+                }
+                _ => "healthy".to_owned(),
+            };
+            let rtt = match health.rtt {
+                Some(rtt) => format!("{:.1}ms", rtt * 1000.0),
+                None => "-".to_owned(),
+            };
+            write!(
+                f,
+                "  {} {}, {} queries, {} failures, rtt {}",
+                upstream.spec, status, health.queries, health.failures, rtt
+            )?;
+            if let Some(dropped) = upstream.client.dropped_replies() {
+                write!(f, ", {} dropped replies", dropped)?;
+            }
+            writeln!(f)?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RETRY: RetryPolicy = RetryPolicy {
+        timeout: Duration::from_millis(20),
+        attempts: 2,
+        backoff: Duration::ZERO,
+    };
+
+    async fn pool(count: u16, strategy: Strategy) -> UpstreamPool {
+        let specs: Vec<UpstreamSpec> = (0..count)
+            .map(|i| UpstreamSpec::Udp(SocketAddr::from(([127, 0, 0, 1], 9000 + i))))
+            .collect();
+        return UpstreamPool::new(
+            &specs,
+            strategy,
+            RETRY,
+            crate::tls::client_config(None).unwrap(),
+            DohMethod::Post,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(
+            "1.1.1.1:5353".parse(),
+            Ok(UpstreamSpec::Udp("1.1.1.1:5353".parse().unwrap()))
+        );
+        assert_eq!(
+            "udp://8.8.8.8".parse(),
+            Ok(UpstreamSpec::Udp("8.8.8.8:53".parse().unwrap()))
+        );
+        assert_eq!(
+            "tls://1.1.1.1:853#cloudflare-dns.com".parse(),
+            Ok(UpstreamSpec::Tls {
+                addr: "1.1.1.1:853".parse().unwrap(),
+                server_name: "cloudflare-dns.com".to_owned(),
+            })
+        );
+        assert_eq!(
+            "tls://[2606:4700::1111]".parse(),
+            Ok(UpstreamSpec::Tls {
+                addr: "[2606:4700::1111]:853".parse().unwrap(),
+                server_name: "2606:4700::1111".to_owned(),
+            })
+        );
+        assert_eq!(
+            "https://8.8.8.8/dns-query#dns.google".parse(),
+            Ok(UpstreamSpec::Https(DohEndpoint {
+                addr: "8.8.8.8:443".parse().unwrap(),
+                host: "dns.google".to_owned(),
+                path: "/dns-query".to_owned(),
+            }))
+        );
+        assert!("https://dns.google/dns-query"
+            .parse::<UpstreamSpec>()
+            .is_err());
+        assert!("dns.google".parse::<UpstreamSpec>().is_err());
+        assert!("tls://1.1.1.1#bad name".parse::<UpstreamSpec>().is_err());
+    }
+
+    #[test]
+    fn test_display_spec() {
+        let spec: UpstreamSpec = "tls://1.1.1.1#one.one.one.one".parse().unwrap();
+        assert_eq!(spec.to_string(), "tls://1.1.1.1:853#one.one.one.one");
+    }
+
+    #[test]
+    fn test_backoff_doubles() {
+        let retry = RetryPolicy {
+            timeout: Duration::from_secs(1),
+            attempts: 3,
+            backoff: Duration::from_millis(100),
+        };
+        assert_eq!(retry.backoff_after(0), Duration::from_millis(100));
+        assert_eq!(retry.backoff_after(2), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_health_benches_after_repeated_failures() {
+        let now = Instant::now();
+        let mut health = Health::default();
+        for _ in 0..FAILURES_BEFORE_BENCHING {
+            assert!(!health.is_benched(now));
+            health.record_failure(now);
+        }
+        assert!(health.is_benched(now));
+        assert!(!health.is_benched(now + BENCH_DURATION));
+        health.record_success(Duration::from_millis(10));
+        assert!(!health.is_benched(now));
+    }
+
+    #[test]
+    fn test_health_rtt_average() {
+        let mut health = Health::default();
+        health.record_success(Duration::from_millis(10));
+        health.record_success(Duration::from_millis(20));
+        assert!((health.rtt.unwrap() - 0.013).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_query_fails_over_to_next_upstream() {
+        // The first upstream never answers; the second echoes each query back as a reply.
+        let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let specs = [
+            UpstreamSpec::Udp(silent.local_addr().unwrap()),
+            UpstreamSpec::Udp(echo.local_addr().unwrap()),
+        ];
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, source) = echo.recv_from(&mut buf).await.unwrap();
+            buf[2] |= 0x80;
+            echo.send_to(&buf[..len], source).await.unwrap();
+        });
+        let tls_config = crate::tls::client_config(None).unwrap();
+        let pool = UpstreamPool::new(&specs, Strategy::Order, RETRY, tls_config, DohMethod::Post)
+            .await
+            .unwrap();
+
+        let query = DnsQuery::deserialize(&[
+            0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 3, b'c', b'o', b'm', 0, 0, 1, 0, 1,
+        ])
+        .unwrap();
+        let (_, answered_by) = pool.query(query).await.unwrap();
+        assert_eq!(answered_by, specs[1]);
+        assert_eq!(pool.upstreams[0].health.lock().unwrap().failures, 1);
+        assert_eq!(pool.upstreams[1].health.lock().unwrap().queries, 1);
+    }
+
+    #[tokio::test]
+    async fn test_order_strategy_skips_benched() {
+        let pool = pool(3, Strategy::Order).await;
+        let now = Instant::now();
+        assert_eq!(pool.candidates(now), [0, 1, 2]);
+        pool.upstreams[0].health.lock().unwrap().benched_until = Some(now + BENCH_DURATION);
+        assert_eq!(pool.candidates(now), [1, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_strategy() {
+        let pool = pool(3, Strategy::RoundRobin).await;
+        let now = Instant::now();
+        assert_eq!(pool.candidates(now), [0, 1, 2]);
+        assert_eq!(pool.candidates(now), [1, 2, 0]);
+        assert_eq!(pool.candidates(now), [2, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_fastest_strategy() {
+        let pool = pool(3, Strategy::Fastest).await;
+        pool.upstreams[0].health.lock().unwrap().rtt = Some(0.05);
+        pool.upstreams[1].health.lock().unwrap().rtt = Some(0.01);
+        pool.upstreams[2].health.lock().unwrap().rtt = Some(0.02);
+        assert_eq!(pool.candidates(Instant::now()), [1, 2, 0]);
+    }
+}
@@ -0,0 +1,344 @@
+use super::{parse_addr, UpstreamError};
+use crate::dns::{DnsQuery, DnsResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use clap::ValueEnum;
+use h2::client::SendRequest;
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::TlsConnector;
+
+/// The media type of a DNS message carried over HTTP.
+pub const DNS_MESSAGE: &str = "application/dns-message";
+
+/// How DNS-over-HTTPS queries are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DohMethod {
+    /// The query is the request body.
+    Post,
+    /// The query is base64url-encoded into the `dns` URL parameter, which caches better.
+    Get,
+}
+
+/// Where a DNS-over-HTTPS resolver lives, parsed from an `https://` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DohEndpoint {
+    /// The address we connect to. It is always given as an IP, because looking a hostname up
+    /// could mean asking ourselves.
+    pub addr: SocketAddr,
+    /// The name the certificate must be valid for, also sent as SNI and as the request's
+    /// authority. Without a `#NAME` it is the IP itself.
+    pub host: String,
+    pub path: String,
+}
+
+impl DohEndpoint {
+    /// Parses `https://IP[:PORT][/PATH][#NAME]`, in the manner of `tls://IP[:PORT][#NAME]`. The
+    /// path defaults to `/dns-query`.
+    pub fn parse(url: &str) -> Option<DohEndpoint> {
+        let rest = url.strip_prefix("https://")?;
+        let (rest, host) = match rest.split_once('#') {
+            Some((rest, name)) => (rest, Some(name)),
+            None => (rest, None),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/dns-query"),
+        };
+        let addr = parse_addr(authority, 443)?;
+        let host = match host {
+            Some(name) => name.to_owned(),
+            None => addr.ip().to_string(),
+        };
+        if ServerName::try_from(host.as_str()).is_err() {
+            return None;
+        }
+        return Some(DohEndpoint {
+            addr,
+            host,
+            path: path.to_owned(),
+        });
+    }
+
+    /// The URL requests are made to, which names `host` rather than the IP we connect to.
+    fn url(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        return match self.addr.port() {
+            443 => format!("https://{}{}", host, self.path),
+            port => format!("https://{}:{}{}", host, port, self.path),
+        };
+    }
+}
+
+impl Display for DohEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let authority = match (self.addr.ip(), self.addr.port()) {
+            (IpAddr::V6(ip), 443) => format!("[{}]", ip),
+            (ip, 443) => ip.to_string(),
+            _ => self.addr.to_string(),
+        };
+        write!(f, "https://{}{}", authority, self.path)?;
+        if self.host != self.addr.ip().to_string() {
+            write!(f, "#{}", self.host)?;
+        }
+        return Ok(());
+    }
+}
+
+/// A resolver reached over DNS-over-HTTPS (RFC 8484). Queries share a single HTTP/2
+/// connection, which is reopened if the server closes it.
+pub struct HttpsUpstream {
+    endpoint: DohEndpoint,
+    method: DohMethod,
+    connector: TlsConnector,
+    connection: Mutex<Option<SendRequest<Bytes>>>,
+}
+
+impl HttpsUpstream {
+    pub fn new(
+        endpoint: DohEndpoint,
+        method: DohMethod,
+        config: Arc<ClientConfig>,
+    ) -> HttpsUpstream {
+        let mut config = (*config).clone();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        return HttpsUpstream {
+            endpoint,
+            method,
+            connector: TlsConnector::from(Arc::new(config)),
+            connection: Mutex::new(None),
+        };
+    }
+
+    pub async fn query(&self, mut query: DnsQuery) -> Result<DnsResponse, UpstreamError> {
+        // RFC 8484 asks for ID 0 so that identical queries are cacheable by HTTP caches.
+        query.header.id = 0;
+        let message = query.serialize();
+        let uri = match self.method {
+            DohMethod::Post => self.endpoint.url(),
+            DohMethod::Get => format!(
+                "{}{}dns={}",
+                self.endpoint.url(),
+                if self.endpoint.path.contains('?') {
+                    '&'
+                } else {
+                    '?'
+                },
+                URL_SAFE_NO_PAD.encode(&message)
+            ),
+        };
+        let mut request = http::Request::builder()
+            .uri(uri)
+            .header(http::header::ACCEPT, DNS_MESSAGE);
+        request = match self.method {
+            DohMethod::Post => request
+                .method(http::Method::POST)
+                .header(http::header::CONTENT_TYPE, DNS_MESSAGE)
+                .header(http::header::CONTENT_LENGTH, message.len()),
+            DohMethod::Get => request.method(http::Method::GET),
+        };
+        let request = request.body(()).map_err(http_error)?;
+
+        let mut sender = self.sender().await?;
+        let end_of_stream = self.method == DohMethod::Get;
+        let (response, mut body) = sender
+            .send_request(request, end_of_stream)
+            .map_err(http_error)?;
+        if self.method == DohMethod::Post {
+            body.send_data(Bytes::from(message), true)
+                .map_err(http_error)?;
+        }
+
+        let response = response.await.map_err(http_error)?;
+        if response.status() != http::StatusCode::OK {
+            return Err(UpstreamError::Http(format!("status {}", response.status())));
+        }
+        let mut body = response.into_body();
+        let mut reply = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(http_error)?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+            reply.extend_from_slice(&chunk);
+            if reply.len() > u16::MAX as usize {
+                return Err(UpstreamError::Http("response too large".to_owned()));
+            }
+        }
+
+        let response = DnsResponse::deserialize(&reply)?;
+        if response.questions != query.questions {
+            return Err(UpstreamError::Mismatched);
+        }
+        return Ok(response);
+    }
+
+    /// A handle on the shared connection that is ready for a new request, connecting first if
+    /// there is no usable connection.
+    async fn sender(&self) -> Result<SendRequest<Bytes>, UpstreamError> {
+        let mut connection = self.connection.lock().await;
+        if let Some(sender) = connection.clone() {
+            if let Ok(sender) = sender.ready().await {
+                return Ok(sender);
+            }
+        }
+        let sender = self.connect().await?;
+        *connection = Some(sender.clone());
+        return sender.ready().await.map_err(http_error);
+    }
+
+    async fn connect(&self) -> Result<SendRequest<Bytes>, UpstreamError> {
+        let server_name = ServerName::try_from(self.endpoint.host.clone())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let tcp = TcpStream::connect(self.endpoint.addr).await?;
+        tcp.set_nodelay(true)?;
+        let tls = self.connector.connect(server_name, tcp).await?;
+        let (sender, connection) = h2::client::handshake(tls).await.map_err(http_error)?;
+        let endpoint = self.endpoint.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                eprintln!("Connection to {} failed: {}", endpoint, e);
+            }
+        });
+        return Ok(sender);
+    }
+}
+
+fn http_error<E: Display>(e: E) -> UpstreamError {
+    return UpstreamError::Http(e.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::ServerConfig;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    const QUERY: [u8; 29] = [
+        0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3,
+        b'c', b'o', b'm', 0, 0, 1, 0, 1,
+    ];
+
+    /// Starts a DNS-over-HTTPS stand-in on 127.0.0.1 that echoes each query back as a reply, for
+    /// both GET and POST, and returns its address, a client config trusting it and a count of
+    /// accepted connections.
+    async fn fake_resolver() -> (SocketAddr, Arc<ClientConfig>, Arc<AtomicUsize>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).unwrap();
+        let cert = CertificateDer::from(certified.cert.der().to_vec());
+        let key = PrivateKeyDer::try_from(certified.key_pair.serialize_der()).unwrap();
+        let mut server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        server_config.alpn_protocols = vec![b"h2".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let tls = acceptor.accept(tcp).await.unwrap();
+                let mut connection = h2::server::handshake(tls).await.unwrap();
+                tokio::spawn(async move {
+                    while let Some(Ok((request, mut respond))) = connection.accept().await {
+                        let mut message = match request.uri().query() {
+                            Some(query) => URL_SAFE_NO_PAD
+                                .decode(query.strip_prefix("dns=").unwrap())
+                                .unwrap(),
+                            None => {
+                                let mut body = request.into_body();
+                                let mut message = Vec::new();
+                                while let Some(chunk) = body.data().await {
+                                    message.extend_from_slice(&chunk.unwrap());
+                                }
+                                message
+                            }
+                        };
+                        message[2] |= 0x80;
+                        let response = http::Response::builder()
+                            .header(http::header::CONTENT_TYPE, DNS_MESSAGE)
+                            .body(())
+                            .unwrap();
+                        let mut body = respond.send_response(response, false).unwrap();
+                        body.send_data(Bytes::from(message), true).unwrap();
+                    }
+                });
+            }
+        });
+        return (addr, Arc::new(client_config), accepted);
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(
+            DohEndpoint::parse("https://8.8.8.8/dns-query#dns.google"),
+            Some(DohEndpoint {
+                addr: "8.8.8.8:443".parse().unwrap(),
+                host: "dns.google".to_owned(),
+                path: "/dns-query".to_owned(),
+            })
+        );
+        assert_eq!(
+            DohEndpoint::parse("https://[2606:4700::1111]:8443"),
+            Some(DohEndpoint {
+                addr: "[2606:4700::1111]:8443".parse().unwrap(),
+                host: "2606:4700::1111".to_owned(),
+                path: "/dns-query".to_owned(),
+            })
+        );
+        // Hostnames would have to be looked up, possibly through ourselves.
+        assert_eq!(DohEndpoint::parse("https://dns.example/dns-query"), None);
+        assert_eq!(DohEndpoint::parse("http://8.8.8.8/dns-query"), None);
+        assert_eq!(DohEndpoint::parse("https://8.8.8.8/#bad name"), None);
+    }
+
+    #[test]
+    fn test_display_endpoint() {
+        let endpoint = DohEndpoint::parse("https://[::1]:8443/resolve").unwrap();
+        assert_eq!(endpoint.to_string(), "https://[::1]:8443/resolve");
+        assert_eq!(endpoint.url(), "https://[::1]:8443/resolve");
+        let endpoint = DohEndpoint::parse("https://8.8.8.8:443/dns-query#dns.google").unwrap();
+        assert_eq!(endpoint.to_string(), "https://8.8.8.8/dns-query#dns.google");
+        assert_eq!(endpoint.url(), "https://dns.google/dns-query");
+    }
+
+    #[tokio::test]
+    async fn test_query_over_one_connection() {
+        let (addr, config, accepted) = fake_resolver().await;
+        let endpoint = DohEndpoint::parse(&format!("https://{}/dns-query", addr)).unwrap();
+        for method in [DohMethod::Post, DohMethod::Get] {
+            let upstream = HttpsUpstream::new(endpoint.clone(), method, config.clone());
+            for _ in 0..2 {
+                let response = upstream
+                    .query(DnsQuery::deserialize(&QUERY).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.header.id, 0);
+                assert_eq!(response.header.qr, 1);
+            }
+        }
+        // One connection for each upstream, reused for its second query.
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,165 @@
+use super::UpstreamError;
+use crate::dns::{DnsQuery, DnsResponse};
+use crate::framing::{frame, read_message};
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// How many open connections we keep around for reuse once they fall idle.
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// A resolver reached over DNS-over-TLS (RFC 7858). Each connection carries one query at a time
+/// and goes back into a small pool once answered, so later queries skip the TCP and TLS
+/// handshakes.
+pub struct TlsUpstream {
+    addr: SocketAddr,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    idle: Mutex<Vec<TlsStream<TcpStream>>>,
+}
+
+impl TlsUpstream {
+    pub fn new(
+        addr: SocketAddr,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> std::io::Result<TlsUpstream> {
+        let server_name = ServerName::try_from(server_name.to_owned())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        return Ok(TlsUpstream {
+            addr,
+            server_name,
+            connector: TlsConnector::from(config),
+            idle: Mutex::new(Vec::new()),
+        });
+    }
+
+    pub async fn query(&self, mut query: DnsQuery) -> Result<DnsResponse, UpstreamError> {
+        query.header.id = rand::random();
+        let message = frame(&query.serialize());
+
+        // The server may have closed an idle connection since we last used it, so a failure on
+        // a reused connection earns one more try on a fresh one.
+        let reused = self.idle.lock().unwrap().pop();
+        if let Some(stream) = reused {
+            if let Ok(response) = self.exchange(stream, &message, &query).await {
+                return Ok(response);
+            }
+        }
+        let stream = self.connect().await?;
+        return self.exchange(stream, &message, &query).await;
+    }
+
+    async fn connect(&self) -> std::io::Result<TlsStream<TcpStream>> {
+        let tcp = TcpStream::connect(self.addr).await?;
+        tcp.set_nodelay(true)?;
+        return self.connector.connect(self.server_name.clone(), tcp).await;
+    }
+
+    async fn exchange(
+        &self,
+        mut stream: TlsStream<TcpStream>,
+        message: &[u8],
+        query: &DnsQuery,
+    ) -> Result<DnsResponse, UpstreamError> {
+        stream.write_all(message).await?;
+        let reply = read_message(&mut stream)
+            .await?
+            .ok_or(UpstreamError::Closed)?;
+        let response = DnsResponse::deserialize(&reply)?;
+        if response.header.id != query.header.id || response.questions != query.questions {
+            return Err(UpstreamError::Mismatched);
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(stream);
+        }
+        return Ok(response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::ServerConfig;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    const QUERY: [u8; 29] = [
+        0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3,
+        b'c', b'o', b'm', 0, 0, 1, 0, 1,
+    ];
+
+    /// Starts a DNS-over-TLS stand-in for `dns.test` that echoes each query back as a reply,
+    /// and returns its address, a client config trusting it and a count of accepted
+    /// connections.
+    async fn fake_resolver() -> (SocketAddr, Arc<ClientConfig>, Arc<AtomicUsize>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["dns.test".to_owned()]).unwrap();
+        let cert = CertificateDer::from(certified.cert.der().to_vec());
+        let key = PrivateKeyDer::try_from(certified.key_pair.serialize_der()).unwrap();
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(tcp).await else {
+                        return;
+                    };
+                    while let Ok(Some(mut message)) = read_message(&mut stream).await {
+                        message[2] |= 0x80;
+                        stream.write_all(&frame(&message)).await.unwrap();
+                    }
+                });
+            }
+        });
+        return (addr, Arc::new(client_config), accepted);
+    }
+
+    #[tokio::test]
+    async fn test_query_reuses_connection() {
+        let (addr, config, accepted) = fake_resolver().await;
+        let upstream = TlsUpstream::new(addr, "dns.test", config).unwrap();
+        for _ in 0..2 {
+            let response = upstream
+                .query(DnsQuery::deserialize(&QUERY).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.header.qr, 1);
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert_eq!(upstream.idle.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_query_rejects_wrong_server_name() {
+        let (addr, config, _) = fake_resolver().await;
+        let upstream = TlsUpstream::new(addr, "other.test", config).unwrap();
+        let result = upstream.query(DnsQuery::deserialize(&QUERY).unwrap()).await;
+        assert!(matches!(result, Err(UpstreamError::Io(_))));
+    }
+}
@@ -0,0 +1,343 @@
+use super::UpstreamError;
+use crate::dns::{DnsQuery, DnsResponse, Question};
+use crate::framing::{frame, read_message};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::oneshot;
+
+/// How many sockets, each bound to its own random port, queries are spread across.
+const SOCKETS: usize = 32;
+
+/// Identifies the reply to a query: the local port it left from, its ID and its question.
+type PendingKey = (u16, u16, Question);
+/// Replies we are still waiting for.
+type Pending = Mutex<HashMap<PendingKey, oneshot::Sender<DnsResponse>>>;
+
+/// A plain DNS resolver reached over UDP. Each query goes out from a randomly chosen socket out
+/// of a small pool under a fresh random ID, so that a spoofed reply has to guess both the port
+/// and the ID. A background task per socket hands each reply to whoever asked that exact
+/// question from that port under that ID; anything else arriving is dropped and counted. Replies
+/// truncated to fit a datagram are fetched again over TCP.
+pub struct UdpUpstream {
+    addr: SocketAddr,
+    /// Each socket with the local port it is bound to.
+    sockets: Vec<(u16, Arc<UdpSocket>)>,
+    pending: Arc<Pending>,
+    /// Datagrams dropped as spoofed, malformed or unsolicited. Counted rather than logged, since
+    /// anyone able to send us datagrams could otherwise flood the log.
+    dropped: Arc<AtomicU64>,
+}
+
+impl UdpUpstream {
+    pub async fn new(addr: SocketAddr) -> std::io::Result<UdpUpstream> {
+        let bind_addr = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let pending = Arc::new(Pending::default());
+        let dropped = Arc::new(AtomicU64::new(0));
+        let mut sockets = Vec::with_capacity(SOCKETS);
+        for _ in 0..SOCKETS {
+            let socket = Arc::new(UdpSocket::bind(bind_addr).await?);
+            let port = socket.local_addr()?.port();
+            tokio::spawn(receive_replies(
+                addr,
+                socket.clone(),
+                port,
+                pending.clone(),
+                dropped.clone(),
+            ));
+            sockets.push((port, socket));
+        }
+        return Ok(UdpUpstream {
+            addr,
+            sockets,
+            pending,
+            dropped,
+        });
+    }
+
+    /// How many datagrams have been dropped instead of being handed to a waiting query.
+    pub fn dropped_replies(&self) -> u64 {
+        return self.dropped.load(Ordering::Relaxed);
+    }
+
+    /// Sends a single-question query and waits for the matching reply. The reply carries our
+    /// random ID rather than the one in `query`, so callers must restore their own.
+    pub async fn query(&self, query: DnsQuery) -> Result<DnsResponse, UpstreamError> {
+        let response = self.query_udp(query.clone()).await?;
+        if response.header.tc == 0 {
+            return Ok(response);
+        }
+        return self.query_tcp(query).await;
+    }
+
+    async fn query_udp(&self, mut query: DnsQuery) -> Result<DnsResponse, UpstreamError> {
+        let question = query.questions[0].clone();
+        let (port, socket) = &self.sockets[rand::random::<usize>() % self.sockets.len()];
+        let (sender, receiver) = oneshot::channel();
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            let id = loop {
+                let id = rand::random::<u16>();
+                if !pending.contains_key(&(*port, id, question.clone())) {
+                    break id;
+                }
+            };
+            pending.insert((*port, id, question.clone()), sender);
+            id
+        };
+        // Take the entry back out if we give up before the reply arrives, so a late reply is
+        // treated as unsolicited.
+        let _guard = PendingGuard {
+            pending: &self.pending,
+            key: Some((*port, id, question)),
+        };
+
+        query.header.id = id;
+        socket.send_to(&query.serialize(), self.addr).await?;
+        return receiver.await.map_err(|_| UpstreamError::Closed);
+    }
+
+    /// Asks again over a one-off TCP connection, where the whole answer fits.
+    async fn query_tcp(&self, mut query: DnsQuery) -> Result<DnsResponse, UpstreamError> {
+        query.header.id = rand::random();
+        let mut stream = TcpStream::connect(self.addr).await?;
+        stream.write_all(&frame(&query.serialize())).await?;
+        let reply = read_message(&mut stream)
+            .await?
+            .ok_or(UpstreamError::Closed)?;
+        let response = DnsResponse::deserialize(&reply)?;
+        if response.header.id != query.header.id || response.questions != query.questions {
+            return Err(UpstreamError::Mismatched);
+        }
+        return Ok(response);
+    }
+}
+
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    key: Option<PendingKey>,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.pending.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+async fn receive_replies(
+    addr: SocketAddr,
+    socket: Arc<UdpSocket>,
+    port: u16,
+    pending: Arc<Pending>,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut buf = vec![0; u16::MAX as usize];
+    loop {
+        let (len, source) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("Error receiving from resolver {}: {}", addr, e);
+                continue;
+            }
+        };
+        if source != addr {
+            dropped.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        let Ok(response) = DnsResponse::deserialize(&buf[..len]) else {
+            dropped.fetch_add(1, Ordering::Relaxed);
+            continue;
+        };
+        let Some(key) = reply_key(port, &response) else {
+            dropped.fetch_add(1, Ordering::Relaxed);
+            continue;
+        };
+        let waiter = pending.lock().unwrap().remove(&key);
+        match waiter {
+            Some(waiter) => {
+                let _ = waiter.send(response);
+            }
+            None => {
+                dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn reply_key(port: u16, response: &DnsResponse) -> Option<PendingKey> {
+    return match response.questions.as_slice() {
+        [question] => Some((port, response.header.id, question.clone())),
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsClass, RData, RecordType, ResourceRecord, ResponseCode};
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    const QUERY: [u8; 29] = [
+        0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3,
+        b'c', b'o', b'm', 0, 0, 1, 0, 1,
+    ];
+
+    /// Answers every query by echoing it back as a reply. When `spoof` is set, each genuine
+    /// reply is preceded by one with the wrong ID and one for a different question.
+    async fn fake_resolver(spoof: bool) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, source) = socket.recv_from(&mut buf).await.unwrap();
+                let mut reply = buf[..len].to_vec();
+                reply[2] |= 0x80;
+                if spoof {
+                    let mut wrong_id = reply.clone();
+                    wrong_id[1] ^= 0xFF;
+                    socket.send_to(&wrong_id, source).await.unwrap();
+                    let mut wrong_question = reply.clone();
+                    wrong_question[13] = b'x';
+                    socket.send_to(&wrong_question, source).await.unwrap();
+                }
+                socket.send_to(&reply, source).await.unwrap();
+            }
+        });
+        return addr;
+    }
+
+    /// Reads queries and never answers them, noting where each came from.
+    async fn silent_resolver() -> (SocketAddr, Arc<Mutex<Vec<SocketAddr>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let sources = Arc::new(Mutex::new(Vec::new()));
+        let received = sources.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (_, source) = socket.recv_from(&mut buf).await.unwrap();
+                received.lock().unwrap().push(source);
+            }
+        });
+        return (addr, sources);
+    }
+
+    /// Answers over UDP with an empty, truncated reply and over TCP, on the same port, with the
+    /// full answer.
+    async fn truncating_resolver() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, source) = socket.recv_from(&mut buf).await.unwrap();
+                let mut reply = buf[..len].to_vec();
+                reply[2] |= 0x82;
+                socket.send_to(&reply, source).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let query = read_message(&mut stream).await.unwrap().unwrap();
+                let query = DnsQuery::deserialize(&query).unwrap();
+                let mut response = DnsResponse::server_failure(&query);
+                response.header.rcode = ResponseCode::NoError;
+                response.answers.push(ResourceRecord::new(
+                    query.questions[0].name.clone(),
+                    DnsClass::IN,
+                    300,
+                    RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                ));
+                stream
+                    .write_all(&frame(&response.serialize()))
+                    .await
+                    .unwrap();
+            }
+        });
+        return addr;
+    }
+
+    #[tokio::test]
+    async fn test_truncated_reply_is_retried_over_tcp() {
+        let upstream = UdpUpstream::new(truncating_resolver().await).await.unwrap();
+        let query = DnsQuery::deserialize(&QUERY).unwrap();
+        let response = upstream.query(query).await.unwrap();
+        assert_eq!(response.header.tc, 0);
+        assert_eq!(response.answers.len(), 1);
+        assert!(upstream.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_query_is_forgotten() {
+        let (addr, sources) = silent_resolver().await;
+        let upstream = UdpUpstream::new(addr).await.unwrap();
+        let query = DnsQuery::deserialize(&QUERY).unwrap();
+        let result = tokio::time::timeout(Duration::from_millis(50), upstream.query(query)).await;
+        assert!(result.is_err());
+        assert_eq!(sources.lock().unwrap().len(), 1);
+        assert!(upstream.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queries_leave_from_different_ports() {
+        let (addr, sources) = silent_resolver().await;
+        let upstream = UdpUpstream::new(addr).await.unwrap();
+        for _ in 0..20 {
+            let query = DnsQuery::deserialize(&QUERY).unwrap();
+            let _ = tokio::time::timeout(Duration::from_millis(5), upstream.query(query)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut ports: Vec<u16> = sources.lock().unwrap().iter().map(|s| s.port()).collect();
+        assert_eq!(ports.len(), 20);
+        ports.sort();
+        ports.dedup();
+        assert!(ports.len() > 1);
+    }
+
+    #[tokio::test]
+    async fn test_query_round_trip() {
+        let upstream = UdpUpstream::new(fake_resolver(false).await).await.unwrap();
+        let query = DnsQuery::deserialize(&QUERY).unwrap();
+        let response = upstream.query(query).await.unwrap();
+        assert_eq!(response.questions[0].qtype, RecordType::A);
+        assert_eq!(response.questions[0].qclass, DnsClass::IN);
+        assert!(upstream.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_queries_get_their_own_replies() {
+        let upstream = Arc::new(UdpUpstream::new(fake_resolver(true).await).await.unwrap());
+        let mut tasks = vec![];
+        for label in [b"aaaaaaa", b"bbbbbbb", b"ccccccc"] {
+            let upstream = upstream.clone();
+            let mut query = QUERY;
+            query[13..20].copy_from_slice(label);
+            tasks.push(tokio::spawn(async move {
+                let query = DnsQuery::deserialize(&query).unwrap();
+                let name = query.questions[0].name.clone();
+                let response = upstream.query(query).await.unwrap();
+                assert_eq!(response.questions[0].name, name);
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert!(upstream.pending.lock().unwrap().is_empty());
+        // Each spoofed reply reached its socket ahead of the genuine one.
+        assert_eq!(upstream.dropped_replies(), 6);
+    }
+}